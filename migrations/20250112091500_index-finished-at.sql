@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE INDEX IF NOT EXISTS build_result_finished_at_idx ON build_result (finished_at DESC);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE build_result ADD COLUMN IF NOT EXISTS log_bytes BIGINT;
@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS schema_meta (
+id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+version INTEGER NOT NULL
+);
+
+INSERT INTO schema_meta (version) VALUES (5)
+ON CONFLICT (id) DO UPDATE SET version = EXCLUDED.version;
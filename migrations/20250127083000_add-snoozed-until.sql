@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE build_result ADD COLUMN IF NOT EXISTS snoozed_until TIMESTAMPTZ;
+
+UPDATE schema_meta SET version = 8;
@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE build_result ADD COLUMN IF NOT EXISTS expected_failure BOOLEAN NOT NULL DEFAULT false;
+
+UPDATE schema_meta SET version = 9;
@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE build_result ADD COLUMN IF NOT EXISTS verified BOOLEAN NOT NULL DEFAULT false;
+
+UPDATE schema_meta SET version = 10;
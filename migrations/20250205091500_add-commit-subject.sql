@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE build_result ADD COLUMN IF NOT EXISTS commit_subject TEXT;
+
+UPDATE schema_meta SET version = 11;
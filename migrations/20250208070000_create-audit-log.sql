@@ -0,0 +1,14 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS audit_log (
+id BIGSERIAL PRIMARY KEY,
+at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+worker TEXT,
+name TEXT NOT NULL,
+arch TEXT NOT NULL,
+status TEXT NOT NULL,
+bytes BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_at_idx ON audit_log (at DESC);
+
+UPDATE schema_meta SET version = 12;
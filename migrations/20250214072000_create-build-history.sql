@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS build_history (
+id BIGSERIAL PRIMARY KEY,
+name TEXT NOT NULL,
+arch TEXT NOT NULL,
+success BOOLEAN NOT NULL,
+log TEXT NOT NULL,
+created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS build_history_name_arch_idx ON build_history (name, arch, id DESC);
+
+UPDATE schema_meta SET version = 14;
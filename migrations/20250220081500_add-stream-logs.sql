@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE build_result ADD COLUMN IF NOT EXISTS stdout_log TEXT;
+ALTER TABLE build_result ADD COLUMN IF NOT EXISTS stderr_log TEXT;
+
+UPDATE schema_meta SET version = 16;
@@ -0,0 +1,211 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use crate::{trim_history, update_status, Package, StatusUpdate};
+
+/// What a queued push writes
+enum Write {
+    Result(Package),
+    /// A push keeping the stored log
+    Status(StatusUpdate),
+}
+
+/// Whether the result was written, `false` for a status update without a stored result
+type Pending = (Write, oneshot::Sender<Result<bool, String>>);
+
+/// Collects build results for a short window and writes them with a single multi-row insert
+pub struct WriteBatcher {
+    tx: mpsc::UnboundedSender<Pending>,
+}
+
+impl WriteBatcher {
+    pub fn spawn(db: PgPool, window: Duration, history_keep: Option<u32>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(db, window, history_keep, rx));
+
+        Self { tx }
+    }
+
+    /// Queue a result and wait until the batch containing it is flushed
+    pub async fn insert(&self, pkg: Package) -> Result<()> {
+        self.queue(Write::Result(pkg)).await.map(|_| ())
+    }
+
+    /// Queue a push keeping the stored log, `false` if there is no stored result to update
+    pub async fn update_status(&self, update: StatusUpdate) -> Result<bool> {
+        self.queue(Write::Status(update)).await
+    }
+
+    async fn queue(&self, write: Write) -> Result<bool> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send((write, done))
+            .map_err(|_| anyhow!("Write batcher is not running"))?;
+
+        wait.await?
+            .map_err(|e| anyhow!("Failed to write build result: {e}"))
+    }
+}
+
+async fn run(
+    db: PgPool,
+    window: Duration,
+    history_keep: Option<u32>,
+    mut rx: mpsc::UnboundedReceiver<Pending>,
+) {
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(window);
+        tokio::pin!(deadline);
+
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                pending = rx.recv() => match pending {
+                    Some(pending) => batch.push(pending),
+                    None => break,
+                },
+            }
+        }
+
+        let (writes, waiters): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        match flush(&db, writes, history_keep).await {
+            Ok(written) => {
+                for (waiter, written) in waiters.into_iter().zip(written) {
+                    waiter.send(Ok(written)).ok();
+                }
+            }
+            Err(e) => {
+                error!("Failed to flush {} build results: {}", waiters.len(), e);
+                for waiter in waiters {
+                    waiter.send(Err(e.to_string())).ok();
+                }
+            }
+        }
+    }
+}
+
+async fn flush(db: &PgPool, writes: Vec<Write>, history_keep: Option<u32>) -> Result<Vec<bool>> {
+    let mut tx = db.begin().await?;
+    let mut written = Vec::with_capacity(writes.len());
+
+    // a status update applies on top of the results queued before it, so each run of
+    // results in between is inserted on its own
+    let mut pkgs = vec![];
+    for write in writes {
+        match write {
+            Write::Result(pkg) => {
+                pkgs.push(pkg);
+                written.push(true);
+            }
+            Write::Status(update) => {
+                insert(&mut tx, std::mem::take(&mut pkgs), history_keep).await?;
+                written.push(update_status(&mut *tx, &update).await?);
+                trim_history(
+                    &mut *tx,
+                    std::slice::from_ref(&update.name),
+                    std::slice::from_ref(&update.arch),
+                    history_keep,
+                )
+                .await?;
+            }
+        }
+    }
+    insert(&mut tx, pkgs, history_keep).await?;
+    tx.commit().await?;
+
+    Ok(written)
+}
+
+async fn insert(
+    tx: &mut Transaction<'_, Postgres>,
+    mut pkgs: Vec<Package>,
+    history_keep: Option<u32>,
+) -> Result<()> {
+    if pkgs.is_empty() {
+        return Ok(());
+    }
+
+    // every push goes into the history, even one superseded within the batch
+    sqlx::query!(
+        "INSERT INTO build_history (name, arch, success, log, created_at) SELECT * FROM UNNEST($1::text[], $2::text[], $3::bool[], $4::text[], $5::timestamptz[])",
+        &pkgs.iter().map(|x| x.name.clone()).collect::<Vec<_>>(),
+        &pkgs.iter().map(|x| x.arch.clone()).collect::<Vec<_>>(),
+        &pkgs.iter().map(|x| x.success).collect::<Vec<_>>(),
+        &pkgs.iter().map(|x| x.log.clone()).collect::<Vec<_>>(),
+        &pkgs.iter().map(|x| x.finished_at).collect::<Vec<_>>()
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    // ON CONFLICT cannot touch the same row twice in one statement, keep the newest result
+    let mut seen = HashSet::new();
+    pkgs.reverse();
+    pkgs.retain(|pkg| seen.insert((pkg.name.clone(), pkg.arch.clone())));
+
+    let mut name = vec![];
+    let mut arch = vec![];
+    let mut success = vec![];
+    let mut log = vec![];
+    let mut finished_at: Vec<DateTime<Utc>> = vec![];
+    let mut log_bytes = vec![];
+    let mut tree_commit = vec![];
+    let mut artifacts = vec![];
+    let mut expected_failure = vec![];
+    let mut verified = vec![];
+    let mut commit_subject = vec![];
+    let mut compressed_bytes = vec![];
+    let mut exit_code = vec![];
+    let mut stdout_log = vec![];
+    let mut stderr_log = vec![];
+
+    for pkg in pkgs {
+        name.push(pkg.name);
+        arch.push(pkg.arch);
+        success.push(pkg.success);
+        log.push(pkg.log);
+        finished_at.push(pkg.finished_at);
+        log_bytes.push(pkg.log_bytes);
+        tree_commit.push(pkg.tree_commit);
+        artifacts.push(pkg.artifacts);
+        expected_failure.push(pkg.expected_failure);
+        verified.push(pkg.verified);
+        commit_subject.push(pkg.commit_subject);
+        compressed_bytes.push(pkg.compressed_bytes);
+        exit_code.push(pkg.exit_code);
+        stdout_log.push(pkg.stdout_log);
+        stderr_log.push(pkg.stderr_log);
+    }
+
+    sqlx::query!(
+        r#"INSERT INTO build_result (name, arch, success, log, finished_at, log_bytes, tree_commit, artifacts, expected_failure, verified, commit_subject, compressed_bytes, exit_code, stdout_log, stderr_log)
+SELECT * FROM UNNEST($1::text[], $2::text[], $3::bool[], $4::text[], $5::timestamptz[], $6::int8[], $7::text[], $8::jsonb[], $9::bool[], $10::bool[], $11::text[], $12::int8[], $13::int4[], $14::text[], $15::text[])
+ON CONFLICT (name, arch) DO UPDATE SET success=EXCLUDED.success, log=EXCLUDED.log, finished_at=EXCLUDED.finished_at, log_bytes=EXCLUDED.log_bytes, tree_commit=EXCLUDED.tree_commit, artifacts=EXCLUDED.artifacts, expected_failure=EXCLUDED.expected_failure, verified=EXCLUDED.verified, commit_subject=EXCLUDED.commit_subject, compressed_bytes=EXCLUDED.compressed_bytes, exit_code=EXCLUDED.exit_code, stdout_log=EXCLUDED.stdout_log, stderr_log=EXCLUDED.stderr_log"#,
+        &name,
+        &arch,
+        &success,
+        &log,
+        &finished_at,
+        &log_bytes as &[Option<i64>],
+        &tree_commit as &[Option<String>],
+        &artifacts as &[Option<serde_json::Value>],
+        &expected_failure,
+        &verified,
+        &commit_subject as &[Option<String>],
+        &compressed_bytes as &[Option<i64>],
+        &exit_code as &[Option<i32>],
+        &stdout_log as &[Option<String>],
+        &stderr_log as &[Option<String>]
+    )
+    .execute(&mut **tx)
+    .await?;
+    // after the dedup above every pair is there once
+    trim_history(&mut **tx, &name, &arch, history_keep).await?;
+
+    Ok(())
+}
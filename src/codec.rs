@@ -0,0 +1,211 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use async_compression::tokio::{bufread, write};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+
+/// Header a worker uses to list the codecs it can compress logs with
+pub const OFFERED_CODECS: &str = "x-reworkit-log-codecs";
+/// Header the server answers with, naming the codec the worker should use
+pub const CHOSEN_CODEC: &str = "x-reworkit-log-codec";
+
+/// Compression of an uploaded log
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+/// Codecs this server can decode, most preferred first
+const SUPPORTED: &[Codec] = &[Codec::Zstd, Codec::Gzip];
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// File extension of a file in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            Codec::Zstd => "zst",
+        }
+    }
+
+    /// Leading bytes of a stream in this format
+    pub fn magic(self) -> &'static [u8] {
+        match self {
+            Codec::Gzip => &[0x1f, 0x8b],
+            Codec::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+        }
+    }
+
+    /// Decoder writing the decompressed stream to `writer`, compressed data is written into it
+    pub fn decoder<'a, W>(self, writer: W) -> Box<dyn AsyncWrite + Unpin + Send + 'a>
+    where
+        W: AsyncWrite + Unpin + Send + 'a,
+    {
+        match self {
+            Codec::Gzip => Box::new(write::GzipDecoder::new(writer)),
+            Codec::Zstd => Box::new(write::ZstdDecoder::new(writer)),
+        }
+    }
+
+    /// Decoder reading the decompressed stream out of `reader`
+    pub fn reader<'a, R>(self, reader: R) -> Box<dyn AsyncRead + Unpin + Send + 'a>
+    where
+        R: AsyncBufRead + Unpin + Send + 'a,
+    {
+        match self {
+            Codec::Gzip => Box::new(bufread::GzipDecoder::new(reader)),
+            Codec::Zstd => Box::new(bufread::ZstdDecoder::new(reader)),
+        }
+    }
+
+    /// Codec of a file going by its extension, `None` if it is not compressed
+    pub fn from_extension(path: &Path) -> Option<Codec> {
+        let ext = path.extension()?;
+        [Codec::Gzip, Codec::Zstd]
+            .into_iter()
+            .find(|x| ext == x.extension())
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            x => Err(anyhow!("Unsupported log compression {x:?}")),
+        }
+    }
+}
+
+/// Pick the best supported codec out of a comma-separated list offered by a worker
+pub fn negotiate(offered: &str) -> Option<Codec> {
+    let offered = offered
+        .split(',')
+        .filter_map(|x| x.parse().ok())
+        .collect::<Vec<Codec>>();
+
+    SUPPORTED.iter().copied().find(|x| offered.contains(x))
+}
+
+/// Codecs an HTTP `Accept-Encoding` header accepts
+///
+/// Codings are matched by name, so `x-gzip` does not count, and one given `q=0` is refused.
+/// `*` stands for every coding not listed.
+pub fn accepted(header: &str) -> Vec<Codec> {
+    let codings: Vec<(String, bool)> = header
+        .split(',')
+        .filter_map(|x| {
+            let mut params = x.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            let refused = params.any(|x| {
+                x.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (!coding.is_empty()).then_some((coding, !refused))
+        })
+        .collect();
+    let find = |name: &str| codings.iter().find(|(x, _)| x == name).map(|(_, ok)| *ok);
+
+    [Codec::Zstd, Codec::Gzip]
+        .into_iter()
+        .filter(|x| find(x.name()).or_else(|| find("*")).unwrap_or(false))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const LOG: &[u8] = b"+ make -j8\nbuild finished\n";
+
+    async fn compress(codec: Codec, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        match codec {
+            Codec::Gzip => {
+                let mut encoder = GzipEncoder::new(&mut out);
+                encoder.write_all(data).await.unwrap();
+                encoder.shutdown().await.unwrap();
+            }
+            Codec::Zstd => {
+                let mut encoder = ZstdEncoder::new(&mut out);
+                encoder.write_all(data).await.unwrap();
+                encoder.shutdown().await.unwrap();
+            }
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            let compressed = compress(codec, LOG).await;
+            assert!(compressed.starts_with(codec.magic()), "{codec:?}");
+
+            let mut decoded = vec![];
+            let mut decoder = codec.decoder(&mut decoded);
+            decoder.write_all(&compressed).await.unwrap();
+            decoder.shutdown().await.unwrap();
+            drop(decoder);
+            assert_eq!(decoded, LOG, "{codec:?}");
+
+            let mut read = vec![];
+            codec
+                .reader(&compressed[..])
+                .read_to_end(&mut read)
+                .await
+                .unwrap();
+            assert_eq!(read, LOG, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate("zstd, gzip"), Some(Codec::Zstd));
+        assert_eq!(negotiate("gzip,zstd"), Some(Codec::Zstd));
+        assert_eq!(negotiate("gzip"), Some(Codec::Gzip));
+        assert_eq!(negotiate("brotli, gzip"), Some(Codec::Gzip));
+        assert_eq!(negotiate("brotli"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn accept_encoding() {
+        assert_eq!(
+            accepted("gzip, deflate, br, zstd"),
+            [Codec::Zstd, Codec::Gzip]
+        );
+        assert_eq!(accepted("GZIP;q=0.5"), [Codec::Gzip]);
+        assert_eq!(accepted("x-gzip"), []);
+        assert_eq!(accepted("gzip;q=0, zstd"), [Codec::Zstd]);
+        assert_eq!(accepted("gzip; q=0.0"), []);
+        assert_eq!(accepted("*"), [Codec::Zstd, Codec::Gzip]);
+        assert_eq!(accepted("*, zstd;q=0"), [Codec::Gzip]);
+        assert_eq!(accepted("identity"), []);
+        assert_eq!(accepted(""), []);
+    }
+
+    #[test]
+    fn names() {
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            assert_eq!(codec.name().parse::<Codec>().unwrap(), codec);
+            let path = format!("foo-amd64.log.{}", codec.extension());
+            assert_eq!(Codec::from_extension(Path::new(&path)), Some(codec));
+        }
+        assert!("xz".parse::<Codec>().is_err());
+        assert_eq!(Codec::from_extension(Path::new("foo-amd64.log")), None);
+    }
+}
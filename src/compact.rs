@@ -0,0 +1,229 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_compression::tokio::bufread::GzipEncoder;
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::{
+    fs,
+    io::{self, BufReader},
+};
+
+use crate::{codec::Codec, compressed_log_path};
+
+/// Files modified more recently than this may still be written by a push and are left alone
+const MIN_AGE: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default, Serialize)]
+pub struct CompactReport {
+    /// Files no result or history entry refers to
+    pub removed_files: u64,
+    pub reclaimed_bytes: u64,
+    /// Logs given the compressed copy `REWORKIT_LOG_STORE_BOTH` keeps
+    pub compressed_files: u64,
+}
+
+/// Remove log files no result or history entry refers to, and with `store_both` compress logs missing their copy
+pub async fn compact(db: &PgPool, log_dir: &Path, store_both: bool) -> Result<CompactReport> {
+    let referenced: HashSet<PathBuf> = sqlx::query_scalar!(
+        r#"SELECT log AS "log!" FROM build_result
+UNION SELECT stdout_log FROM build_result WHERE stdout_log IS NOT NULL
+UNION SELECT stderr_log FROM build_result WHERE stderr_log IS NOT NULL
+UNION SELECT log FROM build_history"#
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(PathBuf::from)
+    .collect();
+
+    let mut report = CompactReport::default();
+    let mut logs = vec![];
+    let mut copies = HashSet::new();
+
+    for (path, metadata) in walk(log_dir).await? {
+        if metadata.modified()?.elapsed().unwrap_or_default() < MIN_AGE {
+            continue;
+        }
+
+        let relative = path.strip_prefix(log_dir)?;
+        let compressed = Codec::from_extension(relative).is_some();
+
+        if referenced.contains(relative) {
+            // compressed by `compress_old`, there is nothing to copy
+            if !compressed {
+                logs.push(relative.to_path_buf());
+            }
+        } else if compressed && referenced.contains(&relative.with_extension("")) {
+            // a compressed copy belongs to the log it was stored next to
+            copies.insert(relative.with_extension(""));
+        } else {
+            fs::remove_file(&path).await?;
+            report.removed_files += 1;
+            report.reclaimed_bytes += metadata.len();
+        }
+    }
+
+    if store_both {
+        for log in logs.iter().filter(|x| !copies.contains(*x)) {
+            gzip_copy(&log_dir.join(log)).await?;
+            report.compressed_files += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+#[derive(Default, Serialize)]
+pub struct CompressReport {
+    pub compressed_files: u64,
+    /// Size of the logs compressed less the size of their compressed versions
+    pub saved_bytes: u64,
+}
+
+/// Gzip the logs of results older than `age`, pointing the results and their history at
+/// `<log>.gz`
+///
+/// Deduplicated objects are shared between results and left as they are.
+pub async fn compress_old(db: &PgPool, log_dir: &Path, age: Duration) -> Result<CompressReport> {
+    let cutoff = Utc::now() - chrono::Duration::from_std(age)?;
+    let logs = sqlx::query_scalar!(
+        r#"SELECT log AS "log!" FROM build_result WHERE log NOT LIKE '%.gz' AND log NOT LIKE 'objects/%' GROUP BY log HAVING max(finished_at) < $1"#,
+        cutoff
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut report = CompressReport::default();
+    for log in logs {
+        let path = log_dir.join(&log);
+        let metadata = match fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        // a push replaced it since the query
+        if metadata.modified()?.elapsed().unwrap_or_default() < age {
+            continue;
+        }
+
+        // REWORKIT_LOG_STORE_BOTH may have kept one already
+        let copy = compressed_log_path(&path, Codec::Gzip);
+        if fs::metadata(&copy).await.is_err() {
+            gzip_copy(&path).await?;
+        }
+
+        sqlx::query!(
+            "UPDATE build_result SET log = $2 WHERE log = $1 AND finished_at < $3",
+            log,
+            format!("{log}.{}", Codec::Gzip.extension()),
+            cutoff
+        )
+        .execute(db)
+        .await?;
+        sqlx::query!(
+            "UPDATE build_history SET log = $2 WHERE log = $1 AND created_at < $3",
+            log,
+            format!("{log}.{}", Codec::Gzip.extension()),
+            cutoff
+        )
+        .execute(db)
+        .await?;
+
+        // a push that replaced the log meanwhile refers to it again, leave it be
+        if fs::metadata(&path)
+            .await?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default()
+            >= age
+        {
+            fs::remove_file(&path).await?;
+        }
+
+        report.compressed_files += 1;
+        report.saved_bytes += metadata
+            .len()
+            .saturating_sub(fs::metadata(&copy).await?.len());
+    }
+
+    Ok(report)
+}
+
+/// Every file below `dir` with its metadata
+async fn walk(dir: &Path) -> Result<Vec<(PathBuf, std::fs::Metadata)>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else if metadata.is_file() {
+                files.push((entry.path(), metadata));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+async fn gzip_copy(path: &Path) -> Result<()> {
+    let copy = compressed_log_path(path, Codec::Gzip);
+    // renamed into place once complete, `/log` would serve a copy cut short by a shutdown
+    let mut tmp = copy.clone().into_os_string();
+    tmp.push(".tmp");
+    let mut encoder = GzipEncoder::new(BufReader::new(fs::File::open(path).await?));
+    let mut f = fs::File::create(&tmp).await?;
+
+    if let Err(e) = io::copy(&mut encoder, &mut f).await {
+        drop(f);
+        fs::remove_file(&tmp).await.ok();
+        return Err(e.into());
+    }
+    fs::rename(&tmp, &copy).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    /// A log file old enough for `compact` to consider
+    fn old_file(path: &Path) {
+        let f = std::fs::File::create(path).unwrap();
+        f.set_modified(SystemTime::now() - 2 * MIN_AGE).unwrap();
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn history_logs_are_kept(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        for name in ["current.log", "history.log", "orphan.log"] {
+            old_file(&log_dir.path().join(name));
+        }
+        sqlx::query("INSERT INTO build_result (name, arch, success, log, finished_at) VALUES ('foo', 'amd64', true, 'current.log', now())")
+            .execute(&db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO build_history (name, arch, success, log) VALUES ('foo', 'amd64', false, 'history.log')")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let report = compact(&db, log_dir.path(), false).await.unwrap();
+        assert_eq!(report.removed_files, 1);
+        assert!(log_dir.path().join("current.log").exists());
+        assert!(log_dir.path().join("history.log").exists());
+        assert!(!log_dir.path().join("orphan.log").exists());
+    }
+}
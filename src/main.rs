@@ -1,29 +1,68 @@
+mod batch;
+mod codec;
+mod compact;
+mod signature;
+mod upload;
+mod watch;
+
 use axum::extract::DefaultBodyLimit;
-use sqlx::{PgPool, Pool, Postgres};
-use std::{path::PathBuf, sync::Arc};
+use batch::WriteBatcher;
+use chrono::{DateTime, Utc};
+use codec::Codec;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sha2::{Digest, Sha256};
+use signature::WorkerKey;
+use sqlx::{PgExecutor, PgPool, Pool, Postgres};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use subtle::ConstantTimeEq;
 
 use anyhow::{anyhow, Context, Result};
-use async_compression::tokio::bufread::GzipDecoder;
 use axum::{
-    extract::{Multipart, Query, State},
-    http::{HeaderMap, StatusCode},
+    async_trait,
+    body::Body,
+    extract::{multipart::MultipartError, FromRequestParts, Multipart, Query, Request, State},
+    http::{
+        header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER, VARY},
+        request::Parts,
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use tokio::{
     fs,
-    io::{self},
+    io::{self, AsyncReadExt},
+    signal::unix::{signal, SignalKind},
 };
-use tracing::{error, info, level_filters::LevelFilter};
+use tokio_util::io::ReaderStream;
+use tracing::{error, info, info_span, level_filters::LevelFilter, warn, Instrument};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use uuid::Uuid;
 
 // learned from https://github.com/tokio-rs/axum/blob/main/examples/anyhow-error-response/src/main.rs
 pub struct AnyhowError(anyhow::Error);
 
 impl IntoResponse for AnyhowError {
     fn into_response(self) -> Response {
+        // including a body over REWORKIT_MAX_LOG_BYTES, which gets 413
+        if let Some(e) = self.0.downcast_ref::<MultipartError>() {
+            info!("Rejecting malformed upload: {}", e);
+            return (e.status(), e.body_text()).into_response();
+        }
+
         info!("Returing internal server error for {}", self.0);
         (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", self.0)).into_response()
     }
@@ -42,11 +81,169 @@ struct AppState {
     secret: String,
     db: Pool<Postgres>,
     log_dir: PathBuf,
+    log_date_layout: bool,
+    log_soft_limit: u64,
+    log_max_bytes: Option<u64>,
+    log_store_both: bool,
+    log_dedup: bool,
+    log_compress_after: Option<Duration>,
+    worker_keys: Vec<WorkerKey>,
+    require_signature: bool,
+    audit_log: bool,
+    maintenance: AtomicBool,
+    batcher: Option<WriteBatcher>,
+    /// History entries kept per package and arch, all of them if `None`
+    history_keep: Option<u32>,
+    metrics: PrometheusHandle,
+}
+
+const REQUEST_ID: &str = "x-request-id";
+
+/// Schema version this server expects, bumped by every migration that updates `schema_meta`
+const SCHEMA_VERSION: i32 = 16;
+
+/// Directory under the log dir holding logs stored by content, see `REWORKIT_LOG_DEDUP`
+const LOG_OBJECTS_DIR: &str = "objects";
+
+/// How often unreferenced log objects are collected, also the age they must reach first
+const LOG_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Seconds a worker is told to wait before retrying a push during maintenance
+const MAINTENANCE_RETRY_AFTER: u64 = 60;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the ReworkIt! server (default)
+    Serve {
+        /// Validate the configuration and exit without starting the server
+        #[arg(long)]
+        check: bool,
+    },
+    /// Print results to the console as they come in
+    Watch {
+        /// Server to watch (default: http:// followed by REWORKIT_URL)
+        #[arg(long, env = "REWORKIT_WATCH_URL")]
+        url: Option<String>,
+        /// Seconds between polls of /recent
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
 }
 
 #[derive(Deserialize)]
 struct GetPackageResultQuery {
     name: String,
+    arch: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GetManyQuery {
+    arch: Option<String>,
+    #[serde(default)]
+    failed_only: bool,
+}
+
+#[derive(Deserialize)]
+struct HealthQuery {
+    #[serde(default)]
+    log_dir: bool,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    name: String,
+    arch: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct FlakyQuery {
+    window: Option<i64>,
+    arch: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    arch: Option<String>,
+    prefix: Option<String>,
+    success: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct FailedQuery {
+    arch: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MissingQuery {
+    arch: String,
+}
+
+#[derive(Deserialize)]
+struct RecentQuery {
+    limit: Option<i64>,
+    #[serde(default)]
+    order: RecentOrder,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecentOrder {
+    #[default]
+    FinishedAtDesc,
+    /// Largest logs first, to find misbehaving builds
+    LogBytesDesc,
+}
+
+#[derive(Deserialize)]
+struct MaintenanceQuery {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct PrettyQuery {
+    pretty: Option<bool>,
+}
+
+/// Whether the client asked for indented JSON with `?pretty=true` or `X-Pretty: true`
+struct Pretty(bool);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Pretty {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let query = Query::<PrettyQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|x| x.pretty);
+        let header = parts
+            .headers
+            .get("X-Pretty")
+            .and_then(|x| x.to_str().ok())
+            .map(|x| matches!(x.trim(), "1" | "true"));
+
+        Ok(Pretty(query.or(header).unwrap_or(false)))
+    }
+}
+
+impl Pretty {
+    fn json<T: Serialize>(&self, value: T) -> Response {
+        if !self.0 {
+            return Json(value).into_response();
+        }
+
+        match serde_json::to_vec_pretty(&value) {
+            Ok(body) => ([(CONTENT_TYPE, "application/json")], body).into_response(),
+            Err(e) => AnyhowError(e.into()).into_response(),
+        }
+    }
 }
 
 #[tokio::main]
@@ -81,137 +278,3013 @@ async fn main() -> Result<()> {
             .init();
     }
 
-    let url = std::env::var("REWORKIT_URL").context("REWORKIT_URL is not set.")?;
-    let secret = std::env::var("REWORKIT_SECRET").context("REWORKIT_SECRET is not set.")?;
-    let pg = std::env::var("REWORKIT_PGCON").context("REWORKIT_PGCON is not set.")?;
-    let log_dir =
-        PathBuf::from(std::env::var("REWORKIT_LOG_DIR").context("REWORKIT_LOG_DIR is not set.")?);
+    let Args { command } = Args::parse();
+
+    match command.unwrap_or(Command::Serve { check: false }) {
+        Command::Serve { check: true } => {
+            if !check_config().await {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Serve { check: false } => serve(Config::from_env()?).await,
+        Command::Watch { url, interval } => {
+            let url = match url {
+                Some(url) => url,
+                None => format!(
+                    "http://{}",
+                    std::env::var("REWORKIT_URL").context("REWORKIT_URL is not set.")?
+                ),
+            };
+            watch::watch(url.trim_end_matches('/'), Duration::from_secs(interval)).await
+        }
+    }
+}
+
+struct Config {
+    url: String,
+    secret: String,
+    pg: String,
+    log_dir: PathBuf,
+    log_date_layout: bool,
+    log_soft_limit: u64,
+    /// Request bodies larger than this are rejected with 413
+    body_limit: usize,
+    /// Uploads decompressing to more than this, 4 GiB unless set, are rejected, guarding
+    /// against decompression bombs
+    log_max_bytes: Option<u64>,
+    /// Keep the compressed upload next to the decompressed log, which costs the
+    /// compressed size in disk space on top of every log
+    log_store_both: bool,
+    /// Store each distinct log once under `log_dir/objects/<sha256>`
+    log_dedup: bool,
+    /// Gzip logs of results older than this in the background
+    log_compress_after: Option<Duration>,
+    /// Public keys pushes signed by workers are verified against
+    worker_keys: Vec<WorkerKey>,
+    /// Reject pushes without a valid signature instead of storing them as unverified
+    require_signature: bool,
+    /// Persist every recorded push to `audit_log`, not just log it
+    audit_log: bool,
+    maintenance: bool,
+    write_batch: Option<Duration>,
+    /// Older history entries of a package and arch are deleted after each push
+    history_keep: Option<u32>,
+    db_connect_attempts: u32,
+    db_connect_delay: Duration,
+}
+
+impl Config {
+    fn from_env() -> Result<Self> {
+        let url = std::env::var("REWORKIT_URL").context("REWORKIT_URL is not set.")?;
+        let secret = std::env::var("REWORKIT_SECRET").context("REWORKIT_SECRET is not set.")?;
+        let pg = std::env::var("REWORKIT_PGCON").context("REWORKIT_PGCON is not set.")?;
+        let log_dir = PathBuf::from(
+            std::env::var("REWORKIT_LOG_DIR").context("REWORKIT_LOG_DIR is not set.")?,
+        );
 
-    let db = PgPool::connect(&pg).await?;
+        let log_date_layout = env_flag("REWORKIT_LOG_DATE_LAYOUT")?;
+        let log_soft_limit =
+            env_parse("REWORKIT_LOG_SOFT_LIMIT_BYTES")?.unwrap_or(50 * 1024 * 1024);
+        let body_limit = env_parse("REWORKIT_MAX_LOG_BYTES")?.unwrap_or(256 * 1024 * 1024);
+        // 0 lifts the limit
+        let log_max_bytes =
+            Some(env_parse("REWORKIT_MAX_DECOMPRESSED_BYTES")?.unwrap_or(4 * 1024 * 1024 * 1024))
+                .filter(|n| *n > 0);
+        let log_store_both = env_flag("REWORKIT_LOG_STORE_BOTH")?;
+        let log_dedup = env_flag("REWORKIT_LOG_DEDUP")?;
+        let log_compress_after = env_parse("REWORKIT_COMPRESS_LOGS_AFTER_DAYS")?
+            .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60));
+        let worker_keys = match std::env::var_os("REWORKIT_WORKER_KEYS") {
+            Some(path) => signature::load_keys(Path::new(&path))?,
+            None => vec![],
+        };
+        let require_signature = env_flag("REWORKIT_REQUIRE_SIGNATURE")?;
+        if require_signature && worker_keys.is_empty() {
+            return Err(anyhow!(
+                "REWORKIT_REQUIRE_SIGNATURE is set but REWORKIT_WORKER_KEYS has no keys"
+            ));
+        }
+        let audit_log = env_flag("REWORKIT_AUDIT_LOG")?;
+        let maintenance = env_flag("REWORKIT_MAINTENANCE")?;
+        let write_batch = env_parse("REWORKIT_WRITE_BATCH_MS")?
+            .filter(|ms| *ms > 0)
+            .map(Duration::from_millis);
+        // 0 keeps the whole history
+        let history_keep =
+            Some(env_parse("REWORKIT_HISTORY_KEEP")?.unwrap_or(50)).filter(|n| *n > 0);
+        let db_connect_attempts = env_parse("REWORKIT_DB_CONNECT_ATTEMPTS")?.unwrap_or(5);
+        let db_connect_delay =
+            Duration::from_secs(env_parse("REWORKIT_DB_CONNECT_DELAY_SECS")?.unwrap_or(2));
 
-    let router = Router::new()
-        .layer(DefaultBodyLimit::disable())
-        .route("/push_log", post(push_log))
-        .route("/get", get(get_package_result))
-        .with_state(Arc::new(AppState {
+        Ok(Self {
+            url,
             secret,
-            db,
+            pg,
             log_dir,
-        }));
+            log_date_layout,
+            log_soft_limit,
+            body_limit,
+            log_max_bytes,
+            log_store_both,
+            log_dedup,
+            log_compress_after,
+            worker_keys,
+            require_signature,
+            audit_log,
+            maintenance,
+            write_batch,
+            history_keep,
+            db_connect_attempts,
+            db_connect_delay,
+        })
+    }
+}
+
+async fn serve(config: Config) -> Result<()> {
+    let Config {
+        url,
+        secret,
+        pg,
+        log_dir,
+        log_date_layout,
+        log_soft_limit,
+        body_limit,
+        log_max_bytes,
+        log_store_both,
+        log_dedup,
+        log_compress_after,
+        worker_keys,
+        require_signature,
+        audit_log,
+        maintenance,
+        write_batch,
+        history_keep,
+        db_connect_attempts,
+        db_connect_delay,
+    } = config;
+
+    if log_store_both {
+        info!("Storing both compressed and decompressed logs, expect extra disk usage");
+    }
+
+    let db = connect_db(&pg, db_connect_attempts, db_connect_delay).await?;
+    let batcher = write_batch.map(|window| WriteBatcher::spawn(db.clone(), window, history_keep));
+    let metrics = install_metrics()?;
+
+    let state = Arc::new(AppState {
+        secret,
+        db,
+        log_dir,
+        log_date_layout,
+        log_soft_limit,
+        log_max_bytes,
+        log_store_both,
+        log_dedup,
+        log_compress_after,
+        worker_keys,
+        require_signature,
+        audit_log,
+        maintenance: AtomicBool::new(maintenance),
+        batcher,
+        history_keep,
+        metrics,
+    });
+
+    tokio::spawn(toggle_maintenance_on_signal(state.clone()));
+    if state.log_dedup {
+        tokio::spawn(collect_log_objects(state.clone()));
+    }
+    if let Some(age) = state.log_compress_after {
+        tokio::spawn(compress_old_logs(state.clone(), age));
+    }
+
     let listener = tokio::net::TcpListener::bind(&url).await?;
-    axum::serve(listener, router).await?;
+    axum::serve(listener, router(state, body_limit))
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    info!("Shut down");
+
+    Ok(())
+}
+
+fn router(state: Arc<AppState>, body_limit: usize) -> Router {
+    Router::new()
+        .route(
+            "/push_log",
+            post(push_log).layer(middleware::from_fn(negotiate_codec)),
+        )
+        .route("/get", get(get_package_result))
+        .route("/list", get(list_packages))
+        .route("/history", get(get_history))
+        .route("/flaky", get(get_flaky))
+        .route("/failed", get(get_failed_results))
+        .route("/stats", get(get_stats))
+        .route("/log", get(get_log))
+        .route("/get_many", post(get_many_package_results))
+        .route("/missing", post(get_missing_packages))
+        .route("/recent", get(get_recent_results))
+        .route("/version", get(get_version))
+        .route("/health", get(health))
+        .route("/metrics", get(get_metrics))
+        .route("/logs/meta", post(get_logs_meta))
+        .route("/auth_check", get(auth_check))
+        .route("/admin/maintenance", post(set_maintenance))
+        .route("/admin/compact", post(admin_compact))
+        .route("/admin/compress", post(admin_compress))
+        .route("/admin/audit", get(admin_audit))
+        .route("/snooze", post(snooze))
+        .route("/result", delete(delete_result))
+        // only applies to the routes above
+        .layer(DefaultBodyLimit::max(body_limit))
+        .layer(middleware::from_fn(request_id))
+        .with_state(state)
+}
+
+/// Resolves on SIGTERM or SIGINT, the server then stops accepting connections
+/// and lets requests in flight, such as pushes writing their logs, finish
+async fn shutdown_signal() {
+    let signals = signal(SignalKind::terminate()).and_then(|term| {
+        let int = signal(SignalKind::interrupt())?;
+        Ok((term, int))
+    });
+    let (mut sigterm, mut sigint) = match signals {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Failed to install SIGTERM and SIGINT handlers: {}", e);
+            return std::future::pending().await;
+        }
+    };
+
+    let name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    info!("Received {name}, shutting down once requests in flight finish");
+}
+
+/// Buckets of the upload size histograms, 1 KiB to 1 GiB in steps of 4
+const SIZE_BUCKETS: &[f64] = &[
+    1024.0,
+    4096.0,
+    16384.0,
+    65536.0,
+    262144.0,
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+    1073741824.0,
+];
+
+/// Exporter with buckets for the size histograms, rendered as summaries otherwise
+fn metrics_builder() -> Result<PrometheusBuilder> {
+    Ok(PrometheusBuilder::new().set_buckets_for_metric(
+        Matcher::Prefix("reworkit_upload_".to_string()),
+        SIZE_BUCKETS,
+    )?)
+}
+
+fn install_metrics() -> Result<PrometheusHandle> {
+    let handle = metrics_builder()?
+        .install_recorder()
+        .context("Failed to install the metrics recorder")?;
+
+    metrics::describe_counter!("reworkit_uploads_total", "Results recorded by success");
+    metrics::describe_counter!(
+        "reworkit_upload_bytes_total",
+        "Compressed bytes of the logs uploaded with recorded results"
+    );
+    metrics::describe_histogram!(
+        "reworkit_upload_compressed_bytes",
+        metrics::Unit::Bytes,
+        "Compressed size of each uploaded log"
+    );
+    metrics::describe_histogram!(
+        "reworkit_upload_log_bytes",
+        metrics::Unit::Bytes,
+        "Decompressed size of each uploaded log"
+    );
+    metrics::describe_counter!("reworkit_get_requests_total", "Requests to /get");
+    metrics::describe_gauge!("reworkit_packages", "Distinct packages with a result");
+
+    Ok(handle)
+}
+
+/// Connect to Postgres, retrying with exponential backoff in case it is still starting up
+async fn connect_db(pg: &str, attempts: u32, delay: Duration) -> Result<PgPool> {
+    let mut delay = delay;
+    let mut attempt = 1;
+
+    loop {
+        match PgPool::connect(pg).await {
+            Ok(db) => return Ok(db),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "({}/{}) Failed to connect to database: {}, retrying in {:?}",
+                    attempt, attempts, e, delay
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e).context(format!(
+                    "Failed to connect to database after {attempt} attempts"
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct CheckReport {
+    failed: bool,
+}
+
+impl CheckReport {
+    fn report(&mut self, item: &str, result: Result<()>) {
+        match result {
+            Ok(()) => println!("[  OK  ] {item}"),
+            Err(e) => {
+                self.failed = true;
+                println!("[FAILED] {item}: {e:#}");
+            }
+        }
+    }
+}
+
+/// Validate the configuration without binding the listener, returns whether it is usable
+async fn check_config() -> bool {
+    let mut report = CheckReport::default();
+
+    let config = match Config::from_env() {
+        Ok(config) => {
+            report.report("Load configuration", Ok(()));
+            config
+        }
+        Err(e) => {
+            report.report("Load configuration", Err(e));
+            return false;
+        }
+    };
+
+    report.report(
+        "Secret is set",
+        if config.secret.is_empty() {
+            Err(anyhow!("REWORKIT_SECRET is empty"))
+        } else {
+            Ok(())
+        },
+    );
+
+    report.report(
+        "Listen address is valid",
+        tokio::net::lookup_host(&config.url)
+            .await
+            .map(|_| ())
+            .with_context(|| format!("Cannot resolve {}", config.url)),
+    );
+
+    report.report(
+        "Database is reachable",
+        async {
+            let db = PgPool::connect(&config.pg).await?;
+            sqlx::query("SELECT 1").execute(&db).await?;
+            Ok(())
+        }
+        .await,
+    );
+
+    report.report(
+        "Log directory is writable",
+        check_log_dir_writable(&config.log_dir).await,
+    );
+
+    !report.failed
+}
+
+async fn check_log_dir_writable(log_dir: &Path) -> Result<()> {
+    fs::create_dir_all(log_dir).await?;
+    // unique, as /health may run this concurrently
+    let probe = log_dir.join(format!(".reworkit-write-check-{}", Uuid::new_v4()));
+    fs::write(&probe, b"").await?;
+    fs::remove_file(&probe).await?;
 
     Ok(())
 }
 
+/// Run the request in a span carrying its `X-Request-Id` and echo the id back
+async fn request_id(req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID)
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap());
+
+    let span = info_span!("request", id = %String::from_utf8_lossy(id.as_bytes()));
+    let mut resp = next.run(req).instrument(span).await;
+    resp.headers_mut().insert(REQUEST_ID, id);
+
+    resp
+}
+
+/// Tell a worker which of the log codecs it offered it should use
+async fn negotiate_codec(req: Request, next: Next) -> Response {
+    let chosen = req
+        .headers()
+        .get(codec::OFFERED_CODECS)
+        .and_then(|x| x.to_str().ok())
+        .and_then(codec::negotiate);
+
+    let mut resp = next.run(req).await;
+    if let Some(chosen) = chosen {
+        resp.headers_mut()
+            .insert(codec::CHOSEN_CODEC, HeaderValue::from_static(chosen.name()));
+    }
+
+    resp
+}
+
+async fn toggle_maintenance_on_signal(state: Arc<AppState>) {
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to install SIGUSR1 handler: {}", e);
+            return;
+        }
+    };
+
+    while sigusr1.recv().await.is_some() {
+        let enabled = !state.maintenance.fetch_xor(true, Ordering::SeqCst);
+        info!("Maintenance mode toggled by SIGUSR1, enabled: {}", enabled);
+    }
+}
+
+/// Periodically remove log objects no result refers to any more
+async fn collect_log_objects(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(LOG_GC_INTERVAL);
+    loop {
+        interval.tick().await;
+        match remove_unreferenced_objects(&state).await {
+            Ok(0) => {}
+            Ok(n) => info!("Removed {} unreferenced log objects", n),
+            Err(e) => warn!("Failed to collect log objects: {}", e),
+        }
+    }
+}
+
+/// Periodically gzip the logs of results older than `age`
+async fn compress_old_logs(state: Arc<AppState>, age: Duration) {
+    let mut interval = tokio::time::interval(LOG_GC_INTERVAL);
+    loop {
+        interval.tick().await;
+        match compact::compress_old(&state.db, &state.log_dir, age).await {
+            Ok(report) if report.compressed_files == 0 => {}
+            Ok(report) => info!(
+                "Compressed {} old logs, saved {} bytes",
+                report.compressed_files, report.saved_bytes
+            ),
+            Err(e) => warn!("Failed to compress old logs: {}", e),
+        }
+    }
+}
+
+async fn remove_unreferenced_objects(state: &AppState) -> Result<usize> {
+    let referenced: HashSet<String> =
+        sqlx::query_scalar!("SELECT log FROM build_result WHERE log LIKE 'objects/%'")
+            .fetch_all(&state.db)
+            .await?
+            .into_iter()
+            .collect();
+
+    let mut entries = match fs::read_dir(state.log_dir.join(LOG_OBJECTS_DIR)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        // compressed copies kept by REWORKIT_LOG_STORE_BOTH go with their object
+        let hash = file_name.split('.').next().unwrap_or_default();
+        if referenced.contains(&format!("{LOG_OBJECTS_DIR}/{hash}")) {
+            continue;
+        }
+
+        // objects written since the grace period may belong to a push not in the database yet
+        let modified = entry.metadata().await?.modified()?;
+        if modified.elapsed().unwrap_or_default() < LOG_GC_INTERVAL {
+            continue;
+        }
+
+        fs::remove_file(entry.path()).await?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+fn env_parse<T>(key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    std::env::var(key)
+        .ok()
+        .map(|x| x.parse())
+        .transpose()
+        .with_context(|| format!("{key} is not valid."))
+}
+
+fn env_flag(key: &str) -> Result<bool> {
+    match std::env::var(key) {
+        Ok(x) => match x.as_str() {
+            "1" | "true" | "yes" => Ok(true),
+            "" | "0" | "false" | "no" => Ok(false),
+            _ => Err(anyhow!("{key} must be a boolean, got {x:?}")),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct Package {
     name: String,
     arch: String,
     success: bool,
     log: String,
+    finished_at: DateTime<Utc>,
+    /// Decompressed log size, unknown if the log could not be written
+    log_bytes: Option<i64>,
+    /// Size of the log as uploaded, unknown for results recorded before it was kept
+    compressed_bytes: Option<i64>,
+    /// Commit of the tree the package was built from
+    tree_commit: Option<String>,
+    /// Subject of the last commit touching the package's directory
+    commit_subject: Option<String>,
+    /// Exit code of the build, unknown if it was killed by a signal or the worker predates it
+    exit_code: Option<i32>,
+    /// Names of the build's stdout and stderr for `/log`, if the worker sent them apart
+    stdout_log: Option<String>,
+    stderr_log: Option<String>,
+    /// Files the build produced, a list of `Artifact`
+    artifacts: Option<serde_json::Value>,
+    /// A known failure is not alerted on until then, set by `/snooze`
+    snoozed_until: Option<DateTime<Utc>>,
+    /// The worker was told this package is expected to fail on this arch
+    expected_failure: bool,
+    /// The push was signed by one of `REWORKIT_WORKER_KEYS`
+    verified: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Artifact {
+    name: String,
+    size: Option<u64>,
+}
+
+/// A push with `keep_log=true`, everything of a `Package` the push can change
+struct StatusUpdate {
+    name: String,
+    arch: String,
+    success: bool,
+    finished_at: DateTime<Utc>,
+    tree_commit: Option<String>,
+    commit_subject: Option<String>,
+    exit_code: Option<i32>,
+    artifacts: Option<serde_json::Value>,
+    expected_failure: bool,
+    verified: bool,
+}
+
+#[derive(Serialize)]
+struct PackageResult {
+    #[serde(flatten)]
+    package: Package,
+    /// Failed builds in a row up to the latest one, counted within the kept history
+    consecutive_failures: i64,
 }
 
+/// Results of a package, 404 if there are none
+///
+/// With `?arch=` that is also the answer for a package known only on other arches.
 async fn get_package_result(
     State(state): State<Arc<AppState>>,
+    pretty: Pretty,
     Query(query): Query<GetPackageResultQuery>,
-) -> Result<Json<Vec<Package>>, AnyhowError> {
+) -> Result<Response, AnyhowError> {
+    metrics::counter!("reworkit_get_requests_total").increment(1);
+
     let packages: Vec<Package> = sqlx::query_as!(
         Package,
-        "SELECT name, arch, success, log FROM build_result WHERE name = $1",
-        query.name
+        "SELECT name, arch, success, log, finished_at, log_bytes, compressed_bytes, tree_commit, commit_subject, exit_code, stdout_log, stderr_log, artifacts, snoozed_until, expected_failure, verified FROM build_result WHERE name = $1 AND ($2::text IS NULL OR arch = $2)",
+        query.name,
+        query.arch
     )
     .fetch_all(&state.db)
     .await?;
 
-    Ok(Json(packages))
-}
-
-async fn push_log(
-    State(state): State<Arc<AppState>>,
-    header: HeaderMap,
-    mut form: Multipart,
-) -> Result<(), AnyhowError> {
-    let log_dir = state.log_dir.clone();
-
-    if header
-        .get("SECRET")
-        .and_then(|x| x.to_str().ok())
-        .map(|x| x != state.secret)
-        .unwrap_or(true)
-    {
-        return Err(anyhow!("Invalid secret token").into());
+    if packages.is_empty() {
+        return Ok((StatusCode::NOT_FOUND, "no results for this package").into_response());
     }
 
-    let mut pkgname = None;
-    let mut arch = None;
-    let mut log_content = Vec::new();
-    let mut success = None;
+    // failures after the last success in the history, all of them if it has none
+    let streaks: HashMap<String, i64> = sqlx::query!(
+        r#"SELECT arch, count(*) AS "failures!" FROM build_history AS h WHERE name = $1 AND ($2::text IS NULL OR arch = $2) AND NOT success AND id > coalesce((SELECT max(id) FROM build_history WHERE name = h.name AND arch = h.arch AND success), 0) GROUP BY arch"#,
+        query.name,
+        query.arch
+    )
+    .fetch_all(&state.db)
+    .await?
+    .into_iter()
+    .map(|x| (x.arch, x.failures))
+    .collect();
 
-    while let Some(field) = form.next_field().await? {
-        match field.name() {
-            Some("package") => {
-                let package_name = field.text().await?;
-                info!("Received package: {}", package_name);
-                pkgname = Some(package_name);
-            }
-            Some("arch") => {
-                let arch_field = field.text().await?;
-                arch = Some(arch_field);
-            }
-            Some("success") => {
-                let success_field = field.text().await?;
-                success = Some(success_field);
-            }
-            Some("log") => {
-                let log = field.bytes().await?;
-                log_content.extend(log);
-            }
-            _ => {
-                info!("Received unknown field: {:?}", field.name());
-            }
-        }
-    }
+    let results: Vec<_> = packages
+        .into_iter()
+        .map(|package| PackageResult {
+            consecutive_failures: streaks.get(&package.arch).copied().unwrap_or_default(),
+            package,
+        })
+        .collect();
 
-    let pkgname = pkgname.context("Missing package field")?;
-    let arch = arch.context("Missing arch field")?;
-    let success = success.context("Missing success field")?;
-    let success = if success == "true" { true } else { false };
-    let filename = Arc::new(format!("{pkgname}-{arch}.log"));
-    let fc = filename.clone();
+    Ok(pretty.json(results))
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = write_log(log_content, log_dir, fc).await {
-            error!("Error writing log: {}", e);
-        }
-    });
+#[derive(Serialize)]
+struct HistoryEntry {
+    name: String,
+    arch: String,
+    success: bool,
+    /// The log as stored at the time, a later push may have replaced it unless
+    /// `REWORKIT_LOG_DATE_LAYOUT` or `REWORKIT_LOG_DEDUP` keeps logs apart
+    log: String,
+    created_at: DateTime<Utc>,
+}
 
-    let pkg = Package {
-        name: pkgname,
-        arch,
-        success,
-        log: filename.to_string(),
-    };
+/// The last `?limit=` results of a package, oldest first, optionally on one arch
+///
+/// Only the newest `REWORKIT_HISTORY_KEEP` entries, 50 by default, of each package and arch
+/// are kept.
+async fn get_history(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Response, AnyhowError> {
+    let limit = query.limit.unwrap_or(20).clamp(1, 1000);
 
-    sqlx::query!(
-        r#"INSERT INTO build_result VALUES ($1, $2, $3, $4)
-ON CONFLICT (name, arch) DO UPDATE SET success=$3, log=$4"#,
-        pkg.name,
-        pkg.arch,
-        pkg.success,
-        pkg.log
+    let entries = sqlx::query_as!(
+        HistoryEntry,
+        "SELECT name, arch, success, log, created_at FROM (SELECT * FROM build_history WHERE name = $1 AND ($2::text IS NULL OR arch = $2) ORDER BY id DESC LIMIT $3) AS h ORDER BY id",
+        query.name,
+        query.arch,
+        limit
     )
-    .fetch_one(&state.db)
+    .fetch_all(&state.db)
     .await?;
 
-    Ok(())
+    Ok(pretty.json(entries))
+}
+
+#[derive(Serialize)]
+struct Flakiness {
+    name: String,
+    arch: String,
+    /// Changes between success and failure within `runs`
+    flips: i64,
+    /// Results looked at, up to `?window=`
+    runs: i64,
 }
 
-async fn write_log(log_content: Vec<u8>, log_dir: PathBuf, fc: Arc<String>) -> Result<()> {
-    fs::create_dir_all(&log_dir).await?;
-    let mut reader = GzipDecoder::new(&*log_content);
-    let mut f = fs::File::create(log_dir.join(&*fc)).await?;
-    io::copy(&mut reader, &mut f).await?;
+/// Packages whose last `?window=` results, 20 by default, flip between success and failure,
+/// most flips first
+///
+/// The window cannot reach further back than the kept history.
+async fn get_flaky(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Query(query): Query<FlakyQuery>,
+) -> Result<Response, AnyhowError> {
+    let window = query.window.unwrap_or(20).clamp(2, 1000);
 
-    Ok(())
+    let flaky = sqlx::query_as!(
+        Flakiness,
+        r#"SELECT name AS "name!", arch AS "arch!", count(*) FILTER (WHERE success <> previous) AS "flips!", count(*) AS "runs!"
+FROM (
+SELECT name, arch, success, lag(success) OVER (PARTITION BY name, arch ORDER BY id) AS previous
+FROM (SELECT *, row_number() OVER (PARTITION BY name, arch ORDER BY id DESC) AS n FROM build_history WHERE $2::text IS NULL OR arch = $2) AS h
+WHERE n <= $1
+) AS h
+GROUP BY name, arch HAVING count(*) FILTER (WHERE success <> previous) > 0
+ORDER BY 3 DESC, name, arch"#,
+        window,
+        query.arch
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(pretty.json(flaky))
+}
+
+/// Names of all packages with a result, optionally on one arch, starting with a prefix or
+/// with that outcome
+async fn list_packages(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Query(query): Query<ListQuery>,
+) -> Result<Response, AnyhowError> {
+    // a prefix is matched literally, not as a pattern
+    let pattern = query.prefix.map(|x| {
+        let escaped = x
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("{escaped}%")
+    });
+
+    let names = sqlx::query_scalar!(
+        "SELECT DISTINCT name FROM build_result WHERE ($1::text IS NULL OR arch = $1) AND ($2::text IS NULL OR name LIKE $2) AND ($3::bool IS NULL OR success = $3) ORDER BY name",
+        query.arch,
+        pattern,
+        query.success
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(pretty.json(names))
+}
+
+/// Every failed result, optionally on one arch, by name
+async fn get_failed_results(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Query(query): Query<FailedQuery>,
+) -> Result<Response, AnyhowError> {
+    let packages: Vec<Package> = sqlx::query_as!(
+        Package,
+        "SELECT name, arch, success, log, finished_at, log_bytes, compressed_bytes, tree_commit, commit_subject, exit_code, stdout_log, stderr_log, artifacts, snoozed_until, expected_failure, verified FROM build_result WHERE NOT success AND ($1::text IS NULL OR arch = $1) ORDER BY name, arch",
+        query.arch
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(pretty.json(packages))
+}
+
+#[derive(Default, Serialize)]
+struct Counts {
+    total: i64,
+    success: i64,
+    failed: i64,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    /// Across all arches
+    #[serde(flatten)]
+    all: Counts,
+    arches: BTreeMap<String, Counts>,
+}
+
+/// Number of results, succeeded and failed, per arch and overall
+async fn get_stats(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+) -> Result<Response, AnyhowError> {
+    let rows = sqlx::query!(
+        r#"SELECT arch, count(*) AS "total!", count(*) FILTER (WHERE success) AS "success!" FROM build_result GROUP BY arch"#
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut stats = Stats {
+        all: Counts::default(),
+        arches: BTreeMap::new(),
+    };
+    for row in rows {
+        let counts = Counts {
+            total: row.total,
+            success: row.success,
+            failed: row.total - row.success,
+        };
+        stats.all.total += counts.total;
+        stats.all.success += counts.success;
+        stats.all.failed += counts.failed;
+        stats.arches.insert(row.arch, counts);
+    }
+
+    Ok(pretty.json(stats))
+}
+
+/// Results of a list of packages in one query, optionally only one arch or only failures
+async fn get_many_package_results(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Query(query): Query<GetManyQuery>,
+    Json(names): Json<Vec<String>>,
+) -> Result<Response, AnyhowError> {
+    let packages: Vec<Package> = sqlx::query_as!(
+        Package,
+        "SELECT name, arch, success, log, finished_at, log_bytes, compressed_bytes, tree_commit, commit_subject, exit_code, stdout_log, stderr_log, artifacts, snoozed_until, expected_failure, verified FROM build_result WHERE name = ANY($1) AND ($2::text IS NULL OR arch = $2) AND (NOT $3 OR NOT success) ORDER BY name, arch",
+        &names,
+        query.arch,
+        query.failed_only
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(pretty.json(packages))
+}
+
+/// Which of the given packages have no result for an arch yet
+async fn get_missing_packages(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Query(query): Query<MissingQuery>,
+    Json(names): Json<Vec<String>>,
+) -> Result<Response, AnyhowError> {
+    let missing = sqlx::query_scalar!(
+        r#"SELECT q.name AS "name!" FROM UNNEST($1::text[]) AS q (name)
+WHERE NOT EXISTS (SELECT 1 FROM build_result b WHERE b.name = q.name AND b.arch = $2)
+ORDER BY q.name"#,
+        &names,
+        query.arch
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(pretty.json(missing))
+}
+
+fn is_authorized(header: &HeaderMap, secret: &str) -> bool {
+    header
+        .get("SECRET")
+        .map(|x| secret_matches(x.as_bytes(), secret.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Compare in constant time, hashing first so the length of the secret does not leak either
+fn secret_matches(given: &[u8], secret: &[u8]) -> bool {
+    Sha256::digest(given).ct_eq(&Sha256::digest(secret)).into()
+}
+
+/// A missing `SECRET` header is as unauthorized as a wrong one
+fn unauthorized() -> Response {
+    (StatusCode::UNAUTHORIZED, "invalid or missing secret").into_response()
+}
+
+/// Lets a worker verify its token before building anything
+async fn auth_check(State(state): State<Arc<AppState>>, header: HeaderMap) -> StatusCode {
+    if is_authorized(&header, &state.secret) {
+        StatusCode::OK
+    } else {
+        StatusCode::UNAUTHORIZED
+    }
+}
+
+async fn set_maintenance(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+    Query(query): Query<MaintenanceQuery>,
+) -> Result<Response, AnyhowError> {
+    if !is_authorized(&header, &state.secret) {
+        return Ok(unauthorized());
+    }
+
+    state.maintenance.store(query.enabled, Ordering::SeqCst);
+    info!("Maintenance mode set by admin, enabled: {}", query.enabled);
+
+    Ok(().into_response())
+}
+
+/// Reclaim space in the log directory, safe to run while pushes come in
+async fn admin_compact(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+) -> Result<Response, AnyhowError> {
+    if !is_authorized(&header, &state.secret) {
+        return Ok(unauthorized());
+    }
+
+    let report = compact::compact(&state.db, &state.log_dir, state.log_store_both).await?;
+    info!(
+        "Compacted logs: removed {} files, reclaimed {} bytes, compressed {} logs",
+        report.removed_files, report.reclaimed_bytes, report.compressed_files
+    );
+
+    Ok(Json(report).into_response())
+}
+
+#[derive(Deserialize)]
+struct CompressQuery {
+    older_than_days: Option<u64>,
+}
+
+/// Gzip the logs of results older than `?older_than_days=`, by default
+/// `REWORKIT_COMPRESS_LOGS_AFTER_DAYS` or 30 days
+async fn admin_compress(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+    Query(query): Query<CompressQuery>,
+) -> Result<Response, AnyhowError> {
+    if !is_authorized(&header, &state.secret) {
+        return Ok(unauthorized());
+    }
+
+    let age = match query.older_than_days {
+        Some(days) => Duration::from_secs(days * 24 * 60 * 60),
+        None => state
+            .log_compress_after
+            .unwrap_or(Duration::from_secs(30 * 24 * 60 * 60)),
+    };
+    let report = compact::compress_old(&state.db, &state.log_dir, age).await?;
+    info!(
+        "Compressed {} old logs, saved {} bytes",
+        report.compressed_files, report.saved_bytes
+    );
+
+    Ok(Json(report).into_response())
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    limit: Option<i64>,
+    worker: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuditEntry {
+    at: DateTime<Utc>,
+    /// Name of the key the push was signed with, `None` if unsigned
+    worker: Option<String>,
+    name: String,
+    arch: String,
+    status: String,
+    /// Compressed size of the uploaded log, 0 if the stored one was kept
+    bytes: i64,
+}
+
+/// Recorded pushes, newest first, persisted with `REWORKIT_AUDIT_LOG`
+async fn admin_audit(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+    pretty: Pretty,
+    Query(query): Query<AuditQuery>,
+) -> Result<Response, AnyhowError> {
+    if !is_authorized(&header, &state.secret) {
+        return Ok(unauthorized());
+    }
+
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    let entries = sqlx::query_as!(
+        AuditEntry,
+        "SELECT at, worker, name, arch, status, bytes FROM audit_log WHERE ($1::text IS NULL OR worker = $1) AND ($2::text IS NULL OR name = $2) ORDER BY at DESC LIMIT $3",
+        query.worker,
+        query.name,
+        limit
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(pretty.json(entries))
+}
+
+#[derive(Deserialize)]
+struct DeleteQuery {
+    name: String,
+    arch: Option<String>,
+}
+
+/// Remove the results of a package dropped from the tree, on every arch unless
+/// `?arch=` is given, along with their history and logs
+///
+/// The history goes too, its entries refer to logs that are removed here.
+async fn delete_result(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+    Query(query): Query<DeleteQuery>,
+) -> Result<Response, AnyhowError> {
+    if !is_authorized(&header, &state.secret) {
+        return Ok(unauthorized());
+    }
+
+    let mut tx = state.db.begin().await?;
+    let deleted = sqlx::query!(
+        "DELETE FROM build_result WHERE name = $1 AND ($2::text IS NULL OR arch = $2) RETURNING log, stdout_log, stderr_log",
+        query.name,
+        query.arch
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if deleted.is_empty() {
+        return Ok((StatusCode::NOT_FOUND, "no results for this package").into_response());
+    }
+
+    let history = sqlx::query_scalar!(
+        "DELETE FROM build_history WHERE name = $1 AND ($2::text IS NULL OR arch = $2) RETURNING log",
+        query.name,
+        query.arch
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let mut logs: BTreeSet<_> = history.into_iter().collect();
+    for result in &deleted {
+        for stream in [&result.stdout_log, &result.stderr_log]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = fs::remove_file(state.log_dir.join(stream)).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove log {stream}: {}", e);
+                }
+            }
+        }
+
+        logs.insert(result.log.clone());
+    }
+    for log in &logs {
+        // objects may be shared with other results, the collector removes them once unreferenced
+        if log.starts_with(&format!("{LOG_OBJECTS_DIR}/")) {
+            continue;
+        }
+
+        let path = state.log_dir.join(log);
+        let copies = [Codec::Gzip, Codec::Zstd].map(|codec| compressed_log_path(&path, codec));
+        for path in std::iter::once(path).chain(copies) {
+            if let Err(e) = fs::remove_file(&path).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to remove log {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+    info!(
+        "Deleted {} results and {} logs of {}",
+        deleted.len(),
+        logs.len(),
+        query.name
+    );
+
+    Ok(().into_response())
+}
+
+#[derive(Deserialize)]
+struct SnoozeRequest {
+    name: String,
+    arch: String,
+    until: DateTime<Utc>,
+}
+
+/// Acknowledge a failing result so it is not alerted on until `until`
+async fn snooze(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+    Json(req): Json<SnoozeRequest>,
+) -> Result<Response, AnyhowError> {
+    if !is_authorized(&header, &state.secret) {
+        return Ok(unauthorized());
+    }
+
+    let updated = sqlx::query!(
+        "UPDATE build_result SET snoozed_until=$3 WHERE name=$1 AND arch=$2",
+        req.name,
+        req.arch,
+        req.until
+    )
+    .execute(&state.db)
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    info!("Snoozed {}-{} until {}", req.name, req.arch, req.until);
+
+    Ok(StatusCode::OK.into_response())
+}
+
+#[derive(Deserialize)]
+struct LogMetaQuery {
+    name: String,
+    arch: String,
+}
+
+#[derive(Serialize)]
+struct LogMeta {
+    name: String,
+    arch: String,
+    exists: bool,
+    bytes: Option<u64>,
+    modified: Option<DateTime<Utc>>,
+}
+
+/// Stat the stored logs of many results at once without sending their contents
+async fn get_logs_meta(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Json(query): Json<Vec<LogMetaQuery>>,
+) -> Result<Response, AnyhowError> {
+    let (names, arches): (Vec<_>, Vec<_>) = query.into_iter().map(|x| (x.name, x.arch)).unzip();
+
+    let rows = sqlx::query!(
+        r#"SELECT q.name AS "name!", q.arch AS "arch!", b.log AS "log?"
+FROM UNNEST($1::text[], $2::text[]) AS q (name, arch)
+LEFT JOIN build_result b ON b.name = q.name AND b.arch = q.arch"#,
+        &names,
+        &arches
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut metas = vec![];
+    for row in rows {
+        let metadata = match &row.log {
+            Some(log) => fs::metadata(state.log_dir.join(log)).await.ok(),
+            None => None,
+        };
+
+        metas.push(LogMeta {
+            name: row.name,
+            arch: row.arch,
+            exists: metadata.is_some(),
+            bytes: metadata.as_ref().map(|x| x.len()),
+            modified: metadata
+                .and_then(|x| x.modified().ok())
+                .map(DateTime::<Utc>::from),
+        });
+    }
+
+    Ok(pretty.json(metas))
+}
+
+#[derive(Deserialize)]
+struct LogQuery {
+    name: String,
+}
+
+/// Stream a stored log by the name `/get` gives for it
+///
+/// A compressed version, as `/admin/compress` or `REWORKIT_LOG_STORE_BOTH`
+/// leave, is sent as is to clients accepting its encoding and decompressed
+/// for the others.
+async fn get_log(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+    Query(query): Query<LogQuery>,
+) -> Result<Response, AnyhowError> {
+    let Some(relative) = log_path(&query.name) else {
+        return Ok((StatusCode::BAD_REQUEST, "invalid log name").into_response());
+    };
+    let path = state.log_dir.join(relative);
+    let accepts = codec::accepted(
+        header
+            .get(ACCEPT_ENCODING)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default(),
+    );
+
+    // a name from before /admin/compress still finds the log
+    let (plain, compressed) = match Codec::from_extension(&path) {
+        Some(codec) => (None, vec![(path, codec)]),
+        None => (
+            Some(path.clone()),
+            [Codec::Zstd, Codec::Gzip]
+                .map(|codec| (compressed_log_path(&path, codec), codec))
+                .to_vec(),
+        ),
+    };
+
+    for (path, codec) in &compressed {
+        if accepts.contains(codec) {
+            if let Some(f) = open_log(path).await? {
+                return Ok(log_response(Box::new(f), Some(*codec)));
+            }
+        }
+    }
+    if let Some(path) = &plain {
+        if let Some(f) = open_log(path).await? {
+            return Ok(log_response(Box::new(f), None));
+        }
+    }
+    for (path, codec) in &compressed {
+        if let Some(f) = open_log(path).await? {
+            return Ok(log_response(codec.reader(io::BufReader::new(f)), None));
+        }
+    }
+
+    Ok((StatusCode::NOT_FOUND, "log not found").into_response())
+}
+
+async fn open_log(path: &Path) -> Result<Option<fs::File>> {
+    match fs::File::open(path).await {
+        Ok(f) => Ok(Some(f)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to open log {}", path.display())),
+    }
+}
+
+fn log_response(
+    reader: Box<dyn io::AsyncRead + Unpin + Send>,
+    encoding: Option<Codec>,
+) -> Response {
+    let mut resp = (
+        [
+            (CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (VARY, "accept-encoding"),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response();
+    if let Some(codec) = encoding {
+        resp.headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static(codec.name()));
+    }
+
+    resp
+}
+
+/// `name` as a path below the log dir, `None` if it could point anywhere else
+fn log_path(name: &str) -> Option<&Path> {
+    let path = Path::new(name);
+    let below = path.components().all(|x| match x {
+        Component::Normal(x) => !x.to_string_lossy().starts_with('.'),
+        _ => false,
+    });
+
+    (!name.is_empty() && below).then_some(path)
+}
+
+/// A boolean form field, strictly `true`/`false` or `1`/`0` so a typo is not taken for `false`
+fn parse_bool(x: &str) -> Option<bool> {
+    match x {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether `x` is usable as part of a file name, without leaving its directory
+///
+/// Only `[A-Za-z0-9._+-]` is allowed, and neither `..` nor a leading `.`, which
+/// the temporary files of uploads start with.
+fn is_file_name(x: &str) -> bool {
+    !x.is_empty()
+        && !x.starts_with('.')
+        && !x.contains("..")
+        && x.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'+' | b'-'))
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Cheap and unauthenticated for load balancers and orchestrators to poll,
+/// `?log_dir=true` also checks the log directory is writable
+async fn health(State(state): State<Arc<AppState>>, Query(query): Query<HealthQuery>) -> Response {
+    let mut result = sqlx::query("SELECT 1")
+        .execute(&state.db)
+        .await
+        .map(|_| ())
+        .context("Database is unreachable");
+    if result.is_ok() && query.log_dir {
+        result = check_log_dir_writable(&state.log_dir)
+            .await
+            .context("Log directory is not writable");
+    }
+
+    match result {
+        Ok(()) => Json(Health {
+            status: "ok",
+            error: None,
+        })
+        .into_response(),
+        Err(e) => {
+            warn!("Health check failed: {:#}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(Health {
+                    status: "error",
+                    error: Some(format!("{e:#}")),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Metrics in the Prometheus text format
+async fn get_metrics(State(state): State<Arc<AppState>>) -> Result<Response, AnyhowError> {
+    // cheap enough to count at scrape time, rather than tracking on every push
+    let packages =
+        sqlx::query_scalar!(r#"SELECT count(DISTINCT name) AS "count!" FROM build_result"#)
+            .fetch_one(&state.db)
+            .await?;
+    metrics::gauge!("reworkit_packages").set(packages as f64);
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+        .into_response())
+}
+
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    schema_version: i32,
+    /// Schema version recorded in the database, `None` if it cannot be read
+    applied_schema_version: Option<i32>,
+}
+
+async fn get_version(State(state): State<Arc<AppState>>, pretty: Pretty) -> Response {
+    let applied_schema_version = sqlx::query_scalar!("SELECT version FROM schema_meta")
+        .fetch_optional(&state.db)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to read schema version: {}", e);
+            None
+        });
+
+    pretty.json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        schema_version: SCHEMA_VERSION,
+        applied_schema_version,
+    })
+}
+
+async fn get_recent_results(
+    State(state): State<Arc<AppState>>,
+    pretty: Pretty,
+    Query(query): Query<RecentQuery>,
+) -> Result<Response, AnyhowError> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 1000);
+    let by_log_bytes = matches!(query.order, RecentOrder::LogBytesDesc);
+
+    let packages: Vec<Package> = sqlx::query_as!(
+        Package,
+        "SELECT name, arch, success, log, finished_at, log_bytes, compressed_bytes, tree_commit, commit_subject, exit_code, stdout_log, stderr_log, artifacts, snoozed_until, expected_failure, verified FROM build_result ORDER BY CASE WHEN $2 THEN log_bytes END DESC NULLS LAST, finished_at DESC LIMIT $1",
+        limit,
+        by_log_bytes
+    )
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(pretty.json(packages))
+}
+
+#[derive(Serialize)]
+struct FieldErrors {
+    errors: Vec<String>,
+}
+
+async fn push_log(
+    State(state): State<Arc<AppState>>,
+    header: HeaderMap,
+    mut form: Multipart,
+) -> Result<Response, AnyhowError> {
+    let log_dir = state.log_dir.clone();
+
+    if state.maintenance.load(Ordering::SeqCst) {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(RETRY_AFTER, MAINTENANCE_RETRY_AFTER.to_string())],
+            "maintenance in progress",
+        )
+            .into_response());
+    }
+
+    if !is_authorized(&header, &state.secret) {
+        return Ok(unauthorized());
+    }
+
+    let mut pkgname = None;
+    let mut arch = None;
+    let mut log_upload = None;
+    let mut stdout_upload = None;
+    let mut stderr_upload = None;
+    let mut duplicates = BTreeSet::new();
+    let mut success = None;
+    let mut compression = None;
+    let mut tree_commit = None;
+    let mut commit_subject = None;
+    let mut exit_code_field = None;
+    let mut artifacts_field = None;
+    let mut expected_failure_field = None;
+    let mut keep_log_field = None;
+
+    while let Some(field) = form.next_field().await? {
+        match field.name() {
+            Some("package") => {
+                let package_name = field.text().await?;
+                info!("Received package: {}", package_name);
+                pkgname = Some(package_name);
+            }
+            Some("arch") => {
+                let arch_field = field.text().await?;
+                arch = Some(arch_field);
+            }
+            Some("success") => {
+                let success_field = field.text().await?;
+                success = Some(success_field);
+            }
+            Some("tree_commit") => {
+                tree_commit = Some(field.text().await?);
+            }
+            Some("commit_subject") => {
+                commit_subject = Some(field.text().await?);
+            }
+            Some("exit_code") => {
+                exit_code_field = Some(field.text().await?);
+            }
+            Some("artifacts") => {
+                artifacts_field = Some(field.text().await?);
+            }
+            Some("compression") => {
+                compression = Some(field.text().await?);
+            }
+            Some("expected_failure") => {
+                expected_failure_field = Some(field.text().await?);
+            }
+            Some("keep_log") => {
+                keep_log_field = Some(field.text().await?);
+            }
+            Some("log") => {
+                // decompressed as it arrives, so the codec has to be known by now
+                let codec = compression
+                    .as_deref()
+                    .and_then(|x| Codec::from_str(x).ok())
+                    .unwrap_or_default();
+                let upload = upload::receive(
+                    field,
+                    codec,
+                    &log_dir,
+                    state.log_max_bytes,
+                    state.log_store_both,
+                )
+                .await?;
+                if log_upload.replace(upload).is_some() {
+                    duplicates.insert("log");
+                }
+            }
+            Some("stdout" | "stderr") => {
+                let (name, slot) = if field.name() == Some("stdout") {
+                    ("stdout", &mut stdout_upload)
+                } else {
+                    ("stderr", &mut stderr_upload)
+                };
+                let codec = compression
+                    .as_deref()
+                    .and_then(|x| Codec::from_str(x).ok())
+                    .unwrap_or_default();
+                let upload =
+                    upload::receive(field, codec, &log_dir, state.log_max_bytes, false).await?;
+                if slot.replace(upload).is_some() {
+                    duplicates.insert(name);
+                }
+            }
+            _ => {
+                info!("Received unknown field: {:?}", field.name());
+            }
+        }
+
+        // both end up in the log file name, so refuse them before receiving any more logs
+        for (field, value) in [("package", &pkgname), ("arch", &arch)] {
+            if let Some(x) = value.as_deref().filter(|x| !is_file_name(x)) {
+                warn!("Rejecting push with {field} {x:?}");
+                return Ok((
+                    StatusCode::BAD_REQUEST,
+                    Json(FieldErrors {
+                        errors: vec![format!(
+                            "{field} field may only contain letters, digits and '._+-', \
+and must not start with '.' or contain '..'"
+                        )],
+                    }),
+                )
+                    .into_response());
+            }
+        }
+    }
+
+    // report every problem at once so the worker operator can fix them together
+    let mut problems = vec![];
+    if pkgname.is_none() {
+        problems.push("missing package field".to_string());
+    }
+    if arch.is_none() {
+        problems.push("missing arch field".to_string());
+    }
+    let parsed_success = match success.as_deref() {
+        None => {
+            problems.push("missing success field".to_string());
+            None
+        }
+        Some(x) => parse_bool(x).or_else(|| {
+            problems.push(format!("success field must be true or false, got {x:?}"));
+            None
+        }),
+    };
+    // workers predating expected failures do not send it
+    let expected_failure = match expected_failure_field.as_deref() {
+        None => false,
+        Some(x) => parse_bool(x).unwrap_or_else(|| {
+            problems.push(format!(
+                "expected_failure field must be true or false, got {x:?}"
+            ));
+            false
+        }),
+    };
+    // sent instead of a log when the status did not change, to keep the stored one
+    let keep_log = match keep_log_field.as_deref() {
+        None => false,
+        Some(x) => parse_bool(x).unwrap_or_else(|| {
+            problems.push(format!("keep_log field must be true or false, got {x:?}"));
+            false
+        }),
+    };
+    // workers predating codec negotiation always send gzip
+    let codec = match compression.as_deref().map(Codec::from_str).transpose() {
+        Ok(codec) => codec.unwrap_or_default(),
+        Err(e) => {
+            problems.push(e.to_string());
+            Codec::default()
+        }
+    };
+    let artifacts = match artifacts_field
+        .as_deref()
+        .map(serde_json::from_str::<Vec<Artifact>>)
+        .transpose()
+    {
+        Ok(artifacts) => artifacts.map(|x| serde_json::json!(x)),
+        Err(e) => {
+            problems.push(format!("artifacts field is not a list of artifacts: {e}"));
+            None
+        }
+    };
+    let exit_code = match exit_code_field
+        .as_deref()
+        .map(str::parse::<i32>)
+        .transpose()
+    {
+        Ok(exit_code) => exit_code,
+        Err(_) => {
+            problems.push(format!(
+                "exit_code field must be an integer, got {:?}",
+                exit_code_field.as_deref().unwrap_or_default()
+            ));
+            None
+        }
+    };
+    for (field, upload) in [
+        ("log", &log_upload),
+        ("stdout", &stdout_upload),
+        ("stderr", &stderr_upload),
+    ] {
+        let Some(upload) = upload else {
+            continue;
+        };
+        if upload.codec != codec {
+            problems.push(format!(
+                "compression field must come before the {field} field"
+            ));
+        } else if !upload.head.starts_with(codec.magic()) {
+            problems.push(format!("{field} field is not {} compressed", codec.name()))
+        }
+    }
+    match (&log_upload, keep_log) {
+        (None, false) => problems.push("missing log field".to_string()),
+        (Some(_), true) => problems.push("keep_log field cannot come with a log field".to_string()),
+        _ => {}
+    }
+    if log_upload.is_none() && (stdout_upload.is_some() || stderr_upload.is_some()) {
+        problems.push("stdout and stderr fields need a log field".to_string());
+    }
+    for field in duplicates {
+        problems.push(format!("{field} field given more than once"));
+    }
+
+    let (Some(pkgname), Some(arch), Some(success_field), Some(success), true) =
+        (pkgname, arch, success, parsed_success, problems.is_empty())
+    else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(FieldErrors { errors: problems }),
+        )
+            .into_response());
+    };
+
+    let signed = signature::Signed {
+        package: &pkgname,
+        arch: &arch,
+        success: &success_field,
+        expected_failure,
+        tree_commit: tree_commit.as_deref(),
+        artifacts: artifacts_field.as_deref(),
+        log_sha256: log_upload.as_ref().map(|x| x.sha256.as_str()),
+        stdout_sha256: stdout_upload.as_ref().map(|x| x.sha256.as_str()),
+        stderr_sha256: stderr_upload.as_ref().map(|x| x.sha256.as_str()),
+        exit_code: exit_code_field.as_deref(),
+        commit_subject: commit_subject.as_deref(),
+    };
+    let signer = match header.get(signature::SIGNATURE) {
+        Some(sig) => match sig
+            .to_str()
+            .ok()
+            .and_then(|sig| signature::verify(&state.worker_keys, &signed.payload(), sig))
+        {
+            Some(key) => Some(key.name.as_str()),
+            None => {
+                warn!(
+                    "Rejecting push of {pkgname}-{arch}: signature does not match any worker key"
+                );
+                return Ok((StatusCode::UNAUTHORIZED, "invalid signature").into_response());
+            }
+        },
+        None if state.require_signature => {
+            return Ok((StatusCode::UNAUTHORIZED, "signature required").into_response());
+        }
+        None => None,
+    };
+    let verified = signer.is_some();
+    let status = match (success, expected_failure) {
+        (true, _) => "success",
+        (false, true) => "expected_failure",
+        (false, false) => "failed",
+    };
+
+    let finished_at = Utc::now();
+
+    // keep_log=true, checked above
+    let Some(upload) = log_upload else {
+        let update = StatusUpdate {
+            name: pkgname.clone(),
+            arch: arch.clone(),
+            success,
+            finished_at,
+            tree_commit,
+            commit_subject,
+            exit_code,
+            artifacts,
+            expected_failure,
+            verified,
+        };
+        let updated = match &state.batcher {
+            Some(batcher) => batcher.update_status(update).await?,
+            None => {
+                let updated = update_status(&state.db, &update).await?;
+                trim_history(
+                    &state.db,
+                    std::slice::from_ref(&update.name),
+                    std::slice::from_ref(&update.arch),
+                    state.history_keep,
+                )
+                .await?;
+                updated
+            }
+        };
+
+        if !updated {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(FieldErrors {
+                    errors: vec![
+                        "keep_log field given, but there is no stored log to keep".to_string()
+                    ],
+                }),
+            )
+                .into_response());
+        }
+
+        audit(&state, signer, &pkgname, &arch, status, 0).await;
+        return Ok(().into_response());
+    };
+
+    let filename = if state.log_date_layout {
+        // log_dir/YYYY/MM/DD/{name}-{arch}.log, so old logs can be pruned by directory
+        format!("{}/{pkgname}-{arch}.log", finished_at.format("%Y/%m/%d"))
+    } else {
+        format!("{pkgname}-{arch}.log")
+    };
+    let filename = Arc::new(filename);
+    let log_bytes = match &upload.decompressed {
+        Ok(n) => {
+            info!(
+                "Log {} is {} bytes, {} bytes compressed",
+                filename, n, upload.compressed_bytes
+            );
+            if *n > state.log_soft_limit {
+                warn!(
+                    "Log {} is {} bytes, over the soft limit of {} bytes",
+                    filename, n, state.log_soft_limit
+                );
+            }
+            *n
+        }
+        Err(e) => return upload_error(&filename, e),
+    };
+    // stored next to the log, `{name}-{arch}.stdout.log` and `{name}-{arch}.stderr.log`
+    let streams: Vec<_> = [("stdout", stdout_upload), ("stderr", stderr_upload)]
+        .into_iter()
+        .filter_map(|(stream, upload)| {
+            let name = format!("{}.{stream}.log", filename.trim_end_matches(".log"));
+            Some((name, upload?))
+        })
+        .collect();
+    for (name, upload) in &streams {
+        if let Err(e) = &upload.decompressed {
+            return upload_error(name, e);
+        }
+    }
+
+    let compressed_bytes = upload.compressed_bytes;
+    let codec = upload.codec;
+    let stored = match write_log(upload, &log_dir, filename.clone(), state.log_dedup).await {
+        Ok(stored) => stored,
+        // the result is not recorded, so it never refers to a log that is not there
+        Err(e) => {
+            error!("Error writing log {}: {}", filename, e);
+            return Err(e.context(format!("Failed to write log {filename}")).into());
+        }
+    };
+
+    let mut stdout_log = None;
+    let mut stderr_log = None;
+    for (name, upload) in streams {
+        if let Err(e) = write_log(upload, &log_dir, Arc::new(name.clone()), false).await {
+            error!("Error writing log {}: {}", name, e);
+            return Err(e.context(format!("Failed to write log {name}")).into());
+        }
+        if name.ends_with(".stdout.log") {
+            stdout_log = Some(name);
+        } else {
+            stderr_log = Some(name);
+        }
+    }
+
+    let pkg = Package {
+        name: pkgname,
+        arch,
+        success,
+        log: stored,
+        finished_at,
+        log_bytes: Some(log_bytes as i64),
+        compressed_bytes: Some(compressed_bytes as i64),
+        tree_commit,
+        commit_subject,
+        exit_code,
+        stdout_log,
+        stderr_log,
+        artifacts,
+        snoozed_until: None,
+        expected_failure,
+        verified,
+    };
+
+    if let Some(batcher) = &state.batcher {
+        let (name, arch) = (pkg.name.clone(), pkg.arch.clone());
+        let log = pkg.log.clone();
+        batcher.insert(pkg).await?;
+        remove_stale_copies(&state, &log, codec).await;
+        record_upload_sizes(compressed_bytes, log_bytes);
+        audit(&state, signer, &name, &arch, status, compressed_bytes).await;
+        return Ok(().into_response());
+    }
+
+    sqlx::query!(
+        r#"WITH history AS (
+INSERT INTO build_history (name, arch, success, log, created_at) VALUES ($1, $2, $3, $4, $5)
+)
+INSERT INTO build_result (name, arch, success, log, finished_at, log_bytes, tree_commit, artifacts, expected_failure, verified, commit_subject, compressed_bytes, exit_code, stdout_log, stderr_log) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+ON CONFLICT (name, arch) DO UPDATE SET success=$3, log=$4, finished_at=$5, log_bytes=$6, tree_commit=$7, artifacts=$8, expected_failure=$9, verified=$10, commit_subject=$11, compressed_bytes=$12, exit_code=$13, stdout_log=$14, stderr_log=$15"#,
+        pkg.name,
+        pkg.arch,
+        pkg.success,
+        pkg.log,
+        pkg.finished_at,
+        pkg.log_bytes,
+        pkg.tree_commit,
+        pkg.artifacts,
+        pkg.expected_failure,
+        pkg.verified,
+        pkg.commit_subject,
+        pkg.compressed_bytes,
+        pkg.exit_code,
+        pkg.stdout_log,
+        pkg.stderr_log
+    )
+    .execute(&state.db)
+    .await?;
+    trim_history(
+        &state.db,
+        std::slice::from_ref(&pkg.name),
+        std::slice::from_ref(&pkg.arch),
+        state.history_keep,
+    )
+    .await?;
+    remove_stale_copies(&state, &pkg.log, codec).await;
+    record_upload_sizes(compressed_bytes, log_bytes);
+    audit(
+        &state,
+        signer,
+        &pkg.name,
+        &pkg.arch,
+        status,
+        compressed_bytes,
+    )
+    .await;
+
+    Ok(().into_response())
+}
+
+/// Apply a push keeping the stored log and record it in the history, `false` if there is
+/// no stored result to update
+pub(crate) async fn update_status<'e>(
+    db: impl PgExecutor<'e>,
+    update: &StatusUpdate,
+) -> Result<bool> {
+    let updated = sqlx::query!(
+        r#"WITH updated AS (
+UPDATE build_result SET success=$3, finished_at=$4, tree_commit=$5, artifacts=$6, expected_failure=$7, verified=$8, commit_subject=$9, exit_code=$10 WHERE name=$1 AND arch=$2
+RETURNING name, arch, success, log, finished_at
+)
+INSERT INTO build_history (name, arch, success, log, created_at) SELECT * FROM updated"#,
+        update.name,
+        update.arch,
+        update.success,
+        update.finished_at,
+        update.tree_commit,
+        update.artifacts,
+        update.expected_failure,
+        update.verified,
+        update.commit_subject,
+        update.exit_code
+    )
+    .execute(db)
+    .await?;
+
+    Ok(updated.rows_affected() > 0)
+}
+
+/// Delete the history of each `names[i]` on `arches[i]` but the newest `keep` entries
+pub(crate) async fn trim_history<'e>(
+    db: impl PgExecutor<'e>,
+    names: &[String],
+    arches: &[String],
+    keep: Option<u32>,
+) -> Result<()> {
+    let Some(keep) = keep else {
+        return Ok(());
+    };
+
+    sqlx::query!(
+        r#"DELETE FROM build_history WHERE id IN (
+SELECT id FROM (
+SELECT id, row_number() OVER (PARTITION BY name, arch ORDER BY id DESC) AS n FROM build_history
+WHERE (name, arch) IN (SELECT * FROM UNNEST($1::text[], $2::text[]))
+) AS h WHERE n > $3
+)"#,
+        names,
+        arches,
+        i64::from(keep)
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Remove compressed files next to a newly recorded log that are not a copy of it,
+/// left by an earlier push or `/admin/compress`
+///
+/// Only done once the result refers to the new log, so it never refers to a removed one.
+async fn remove_stale_copies(state: &AppState, log: &str, codec: Codec) {
+    // an object is the same log whatever codec its copies were made with
+    if state.log_dedup {
+        return;
+    }
+
+    let path = state.log_dir.join(log);
+    for stale in [Codec::Gzip, Codec::Zstd] {
+        if state.log_store_both && stale == codec {
+            continue;
+        }
+        if let Err(e) = fs::remove_file(compressed_log_path(&path, stale)).await {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove stale copy of {log}: {}", e);
+            }
+        }
+    }
+}
+
+fn record_upload_sizes(compressed_bytes: u64, log_bytes: u64) {
+    metrics::histogram!("reworkit_upload_compressed_bytes").record(compressed_bytes as f64);
+    metrics::histogram!("reworkit_upload_log_bytes").record(log_bytes as f64);
+}
+
+/// Record which worker reported a result in the log and metrics, `worker` is
+/// `None` for unsigned pushes
+///
+/// The audit trail is best effort, failing to persist it does not fail the push.
+async fn audit(
+    state: &AppState,
+    worker: Option<&str>,
+    name: &str,
+    arch: &str,
+    status: &str,
+    bytes: u64,
+) {
+    info!(
+        worker = worker.unwrap_or("anonymous"),
+        package = name,
+        arch,
+        status,
+        bytes,
+        "Recorded push"
+    );
+    let success = if status == "success" { "true" } else { "false" };
+    metrics::counter!("reworkit_uploads_total", "success" => success).increment(1);
+    metrics::counter!("reworkit_upload_bytes_total").increment(bytes);
+
+    if !state.audit_log {
+        return;
+    }
+    if let Err(e) = sqlx::query!(
+        "INSERT INTO audit_log (worker, name, arch, status, bytes) VALUES ($1, $2, $3, $4, $5)",
+        worker,
+        name,
+        arch,
+        status,
+        bytes as i64
+    )
+    .execute(&state.db)
+    .await
+    {
+        warn!("Failed to persist audit entry for {name}-{arch}: {}", e);
+    }
+}
+
+/// The log decompressed to more than `REWORKIT_MAX_DECOMPRESSED_BYTES`
+#[derive(Debug)]
+struct LogTooLarge(u64);
+
+impl std::fmt::Display for LogTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "log decompresses to more than {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for LogTooLarge {}
+
+/// The log is truncated or does not match its checksum
+#[derive(Debug)]
+struct LogCorrupt(io::Error);
+
+impl std::fmt::Display for LogCorrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "log failed to decompress: {}", self.0)
+    }
+}
+
+impl std::error::Error for LogCorrupt {}
+
+/// Move a received log into place in `log_dir`, returns where it was stored relative to `log_dir`
+///
+/// The log was decompressed next to its destination and is renamed into place, so
+/// a failed write never leaves a partial log behind.
+/// With `REWORKIT_LOG_STORE_BOTH` the upload is also kept as is, e.g. `foo-amd64.log.gz`.
+/// With `dedup` the log is moved to `objects/<sha256>` instead of staying at `fc`.
+async fn write_log(
+    upload: upload::Upload,
+    log_dir: &Path,
+    fc: Arc<String>,
+    dedup: bool,
+) -> Result<String> {
+    let mut stored = fc.to_string();
+    let mut path = log_dir.join(&*fc);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    fs::File::open(&upload.tmp).await?.sync_all().await?;
+
+    if dedup {
+        stored = format!("{LOG_OBJECTS_DIR}/{}", sha256_file(&upload.tmp).await?);
+        path = log_dir.join(&stored);
+        fs::create_dir_all(log_dir.join(LOG_OBJECTS_DIR)).await?;
+    }
+
+    if let Some(copy) = &upload.compressed_tmp {
+        fs::rename(copy, compressed_log_path(&path, upload.codec)).await?;
+    }
+
+    // with dedup, replacing an identical object refreshes its mtime, keeping it from the collector
+    fs::rename(&upload.tmp, &path).await?;
+
+    Ok(stored)
+}
+
+/// The response refusing an upload that could not be decompressed
+fn upload_error(filename: &str, e: &anyhow::Error) -> Result<Response, AnyhowError> {
+    let status = if e.is::<LogCorrupt>() {
+        // a retry will most likely upload it intact
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else if e.is::<LogTooLarge>() {
+        StatusCode::BAD_REQUEST
+    } else {
+        error!("Error writing log {}: {}", filename, e);
+        return Err(anyhow!("Failed to write log {filename}: {e}").into());
+    };
+
+    warn!("Rejecting log {}: {}", filename, e);
+    Ok((
+        status,
+        Json(FieldErrors {
+            errors: vec![e.to_string()],
+        }),
+    )
+        .into_response())
+}
+
+async fn sha256_file(path: &Path) -> Result<String> {
+    let mut f = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Where the compressed copy of a log is kept when storing both
+fn compressed_log_path(path: &Path, codec: Codec) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(codec.extension());
+
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::write::GzipEncoder;
+    use http_body_util::BodyExt;
+    use sqlx::postgres::PgPoolOptions;
+    use tokio::io::AsyncWriteExt;
+    use tower::ServiceExt;
+
+    const SECRET: &str = "s3cret";
+    const BOUNDARY: &str = "reworkit-test-boundary";
+
+    fn test_state(db: PgPool, log_dir: &Path) -> Arc<AppState> {
+        Arc::new(AppState {
+            secret: SECRET.to_string(),
+            db,
+            log_dir: log_dir.to_path_buf(),
+            log_date_layout: false,
+            log_soft_limit: 50 * 1024 * 1024,
+            log_max_bytes: None,
+            log_store_both: false,
+            log_dedup: false,
+            log_compress_after: None,
+            worker_keys: vec![],
+            require_signature: false,
+            audit_log: false,
+            maintenance: AtomicBool::new(false),
+            batcher: None,
+            history_keep: Some(50),
+            metrics: PrometheusBuilder::new().build_recorder().handle(),
+        })
+    }
+
+    /// For requests answered before the database is used
+    fn no_db() -> PgPool {
+        PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap()
+    }
+
+    async fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzipEncoder::new(vec![]);
+        encoder.write_all(data).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
+    /// A push of these multipart fields, in this order
+    fn push(fields: &[(&str, &[u8])]) -> Request {
+        let mut body = vec![];
+        for (name, value) in fields {
+            body.extend(
+                format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"").bytes(),
+            );
+            if matches!(*name, "log" | "stdout" | "stderr") {
+                body.extend(b"; filename=\"log.gz\"");
+            }
+            body.extend(b"\r\n\r\n");
+            body.extend(*value);
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{BOUNDARY}--\r\n").bytes());
+
+        Request::post("/push_log")
+            .header("SECRET", SECRET)
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// A push of a result with a gzip compressed log
+    async fn push_result(package: &str, arch: &str, success: bool, log: &[u8]) -> Request {
+        let log = gzip(log).await;
+        push(&[
+            ("package", package.as_bytes()),
+            ("arch", arch.as_bytes()),
+            ("success", success.to_string().as_bytes()),
+            ("log", &log),
+        ])
+    }
+
+    async fn get_json(state: Arc<AppState>, uri: &str) -> (StatusCode, serde_json::Value) {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let (status, body) = send(state, req).await;
+
+        (status, serde_json::from_str(&body).unwrap_or_default())
+    }
+
+    async fn send(state: Arc<AppState>, req: Request) -> (StatusCode, String) {
+        let resp = router(state, usize::MAX).oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+
+        (status, String::from_utf8_lossy(&body).to_string())
+    }
+
+    #[test]
+    fn secrets() {
+        assert!(secret_matches(b"s3cret", b"s3cret"));
+        // same length, differing in the last byte
+        assert!(!secret_matches(b"s3crex", b"s3cret"));
+        assert!(!secret_matches(b"s3cre", b"s3cret"));
+        assert!(!secret_matches(b"s3cret-and-more", b"s3cret"));
+        assert!(!secret_matches(b"", b"s3cret"));
+    }
+
+    #[tokio::test]
+    async fn wrong_secret_is_unauthorized() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(no_db(), log_dir.path());
+        for secret in ["s3crex", "s3cre", ""] {
+            let req = Request::get("/auth_check")
+                .header("SECRET", secret)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(send(state.clone(), req).await.0, StatusCode::UNAUTHORIZED);
+        }
+
+        let req = Request::get("/auth_check")
+            .header("SECRET", SECRET)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(state, req).await.0, StatusCode::OK);
+    }
+
+    #[test]
+    fn booleans() {
+        for (x, expected) in [("true", true), ("1", true), ("false", false), ("0", false)] {
+            assert_eq!(parse_bool(x), Some(expected), "{x:?}");
+        }
+        for x in [
+            "True", "FALSE", "yes", "no", "", " true", "false\n", "2", "-1",
+        ] {
+            assert_eq!(parse_bool(x), None, "{x:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_success_is_rejected() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"True"),
+        ]);
+
+        let (status, body) = send(test_state(no_db(), log_dir.path()), req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body.contains(r#"success field must be true or false, got \"True\""#),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_expected_failure_is_rejected() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"false"),
+            ("expected_failure", b"yes"),
+        ]);
+
+        let (status, body) = send(test_state(no_db(), log_dir.path()), req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body.contains(r#"expected_failure field must be true or false, got \"yes\""#),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn file_names() {
+        for name in [
+            "gtk+",
+            "libstdc++-compat",
+            "python-3.12",
+            "amd64",
+            "loongarch64",
+        ] {
+            assert!(is_file_name(name), "{name} is rejected");
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            "../../etc/cron.d/x",
+            "foo/bar",
+            "foo\\bar",
+            "foo..bar",
+            ".upload-x",
+            "foo\0",
+            "foo bar",
+            "fő",
+        ] {
+            assert!(!is_file_name(name), "{name:?} is accepted");
+        }
+    }
+
+    #[tokio::test]
+    async fn traversal_is_rejected_before_the_log() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let log = gzip(b"pwned").await;
+        let req = push(&[
+            ("package", b"../../etc/cron.d/x"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+            ("log", &log),
+        ]);
+
+        let (status, body) = send(test_state(no_db(), log_dir.path()), req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("package field"), "{body}");
+        // not even the temporary file of the log was written
+        assert_eq!(std::fs::read_dir(log_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn nul_in_arch_is_rejected() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64\0"),
+            ("success", b"true"),
+        ]);
+
+        let (status, body) = send(test_state(no_db(), log_dir.path()), req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("arch field"), "{body}");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn plus_in_package_name(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let req = push_result("gtk+", "amd64", true, b"building gtk+").await;
+
+        let (status, body) = send(test_state(db, log_dir.path()), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            std::fs::read(log_dir.path().join("gtk+-amd64.log")).unwrap(),
+            b"building gtk+"
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn second_push_updates_the_result(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+
+        let req = push_result("foo", "amd64", false, b"first").await;
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let req = push_result("foo", "amd64", true, b"second").await;
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, results) = get_json(state, "/get?name=foo").await;
+        assert_eq!(status, StatusCode::OK);
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["success"], true);
+        assert_eq!(
+            std::fs::read(log_dir.path().join(results[0]["log"].as_str().unwrap())).unwrap(),
+            b"second"
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn large_log_is_written_while_it_arrives(db: PgPool) {
+        use axum::body::Bytes;
+
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        // random letters of four, so the upload is still a quarter of the log, over a megabyte
+        let mut x = 0x2545_f491_4f6c_dd1d_u64;
+        let log: Vec<u8> = (0..6 * 1024 * 1024)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                b"acgt"[x as usize % 4]
+            })
+            .collect();
+        let (parts, body) = push_result("big", "amd64", true, &log).await.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+
+        let (tx, rx) = tokio::sync::mpsc::channel::<Bytes>(1);
+        let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|x| (Ok::<_, std::io::Error>(x), rx))
+        });
+        let req = Request::from_parts(parts, Body::from_stream(stream));
+        let pushing = tokio::spawn(send(state, req));
+
+        let half = body.len() / 2;
+        for chunk in body[..half].chunks(64 * 1024) {
+            tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        // most of the first half is on disk before the rest is sent, so the log is not
+        // collected in memory first
+        let written = || -> u64 {
+            std::fs::read_dir(log_dir.path())
+                .unwrap()
+                .map(|x| x.unwrap().metadata().unwrap().len())
+                .sum()
+        };
+        let mut waited = 0;
+        while written() < log.len() as u64 / 4 {
+            assert!(waited < 100, "only {} bytes written after 5s", written());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            waited += 1;
+        }
+        for chunk in body[half..].chunks(64 * 1024) {
+            tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        drop(tx);
+
+        let (status, body) = pushing.await.unwrap();
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let stored = std::fs::read(log_dir.path().join("big-amd64.log")).unwrap();
+        assert!(stored == log, "stored log differs from the uploaded one");
+        // only the stored log is left, no temporary files
+        assert_eq!(std::fs::read_dir(log_dir.path()).unwrap().count(), 1);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn get_filters_by_arch(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        for arch in ["amd64", "arm64"] {
+            let req = push_result("foo", arch, arch == "amd64", b"log").await;
+            let (status, body) = send(state.clone(), req).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+
+        let (status, results) = get_json(state.clone(), "/get?name=foo&arch=arm64").await;
+        assert_eq!(status, StatusCode::OK);
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["arch"], "arm64");
+        assert_eq!(results[0]["success"], false);
+
+        let (status, results) = get_json(state.clone(), "/get?name=foo").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(results.as_array().unwrap().len(), 2);
+
+        let (status, _) = get_json(state, "/get?name=foo&arch=riscv64").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn stats_count_per_arch(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        for (package, arch, success) in [
+            ("foo", "amd64", true),
+            ("bar", "amd64", false),
+            ("baz", "amd64", true),
+            ("foo", "arm64", false),
+        ] {
+            let req = push_result(package, arch, success, b"log").await;
+            let (status, body) = send(state.clone(), req).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+
+        let (status, stats) = get_json(state, "/stats").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            stats,
+            serde_json::json!({
+                "total": 4,
+                "success": 2,
+                "failed": 2,
+                "arches": {
+                    "amd64": { "total": 3, "success": 2, "failed": 1 },
+                    "arm64": { "total": 1, "success": 0, "failed": 1 },
+                },
+            })
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn log_sizes_are_stored(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        let log = b"a log line\n".repeat(1000);
+        let compressed = gzip(&log).await;
+
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+            ("log", &compressed),
+        ]);
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (_, results) = get_json(state, "/get?name=foo").await;
+        let result = &results[0];
+        let path = log_dir.path().join(result["log"].as_str().unwrap());
+        assert_eq!(result["log_bytes"], std::fs::metadata(path).unwrap().len());
+        assert_eq!(result["log_bytes"], log.len());
+        assert_eq!(result["compressed_bytes"], compressed.len());
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn recent_orders_by_log_bytes(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        for (name, lines) in [("small", 1), ("large", 100), ("medium", 10)] {
+            let req = push_result(name, "amd64", true, &b"a log line\n".repeat(lines)).await;
+            let (status, body) = send(state.clone(), req).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+
+        let names = |results: serde_json::Value| -> Vec<String> {
+            results
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let (_, results) = get_json(state.clone(), "/recent?order=log_bytes_desc&limit=2").await;
+        assert_eq!(names(results), ["large", "medium"]);
+        let (_, results) = get_json(state, "/recent").await;
+        assert_eq!(names(results), ["medium", "large", "small"]);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn zstd_log_is_decoded(db: PgPool) {
+        use async_compression::tokio::write::ZstdEncoder;
+
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        let mut encoder = ZstdEncoder::new(vec![]);
+        encoder.write_all(b"zstd log").await.unwrap();
+        encoder.shutdown().await.unwrap();
+
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+            ("compression", b"zstd"),
+            ("log", &encoder.into_inner()),
+        ]);
+        let (status, body) = send(state, req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            std::fs::read(log_dir.path().join("foo-amd64.log")).unwrap(),
+            b"zstd log"
+        );
+    }
+
+    #[tokio::test]
+    async fn garbage_log_is_rejected() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(no_db(), log_dir.path());
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+            ("log", b"definitely not gzip"),
+        ]);
+
+        let (status, body) = send(state, req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("log field is not gzip compressed"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn corrupt_gzip_log_is_rejected() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(no_db(), log_dir.path());
+        let mut log = gzip(&b"a log line\n".repeat(1000)).await;
+        // keep the header, mangle the deflate stream after it
+        for x in &mut log[10..] {
+            *x = !*x;
+        }
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+            ("log", &log),
+        ]);
+
+        let (status, body) = send(state, req).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+        assert_eq!(std::fs::read_dir(log_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn missing_log_needs_keep_log() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+        ]);
+        let (status, body) = send(test_state(no_db(), log_dir.path()), req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("missing log field"), "{body}");
+
+        let log = gzip(b"log").await;
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+            ("keep_log", b"true"),
+            ("log", &log),
+        ]);
+        let (status, body) = send(test_state(no_db(), log_dir.path()), req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body.contains("keep_log field cannot come with a log field"),
+            "{body}"
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn batched_push_keeps_the_stored_log(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut state = Arc::into_inner(test_state(db.clone(), log_dir.path())).unwrap();
+        state.batcher = Some(WriteBatcher::spawn(
+            db,
+            Duration::from_millis(10),
+            state.history_keep,
+        ));
+        let state = Arc::new(state);
+        let keep_log = || {
+            push(&[
+                ("package", b"foo"),
+                ("arch", b"amd64"),
+                ("success", b"false"),
+                ("keep_log", b"true"),
+            ])
+        };
+
+        // nothing stored yet to keep
+        let (status, body) = send(state.clone(), keep_log()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("no stored log to keep"), "{body}");
+
+        let req = push_result("foo", "amd64", true, b"log").await;
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (_, before) = get_json(state.clone(), "/get?name=foo&arch=amd64").await;
+
+        let (status, body) = send(state.clone(), keep_log()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (_, after) = get_json(state.clone(), "/get?name=foo&arch=amd64").await;
+        assert_eq!(after[0]["success"], false);
+        assert_eq!(after[0]["log"], before[0]["log"]);
+        assert_eq!(history_len(state).await, 2);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn deleting_a_result_removes_its_history_logs(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db.clone(), log_dir.path());
+        let req = push_result("foo", "amd64", true, b"log").await;
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        // an older push under the date layout, only the history still refers to it
+        std::fs::create_dir_all(log_dir.path().join("2020/01/01")).unwrap();
+        std::fs::write(log_dir.path().join("2020/01/01/foo-amd64.log"), "old log").unwrap();
+        sqlx::query(
+            "INSERT INTO build_history (name, arch, success, log, created_at) VALUES ('foo', 'amd64', false, '2020/01/01/foo-amd64.log', '2020-01-01')",
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let req = Request::delete("/result?name=foo")
+            .header("SECRET", SECRET)
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(!log_dir.path().join("foo-amd64.log").exists());
+        assert!(!log_dir.path().join("2020/01/01/foo-amd64.log").exists());
+        assert_eq!(history_len(state).await, 0);
+    }
+
+    /// Number of history entries of foo on amd64
+    async fn history_len(state: Arc<AppState>) -> usize {
+        let (status, entries) = get_json(state, "/history?name=foo&arch=amd64&limit=1000").await;
+        assert_eq!(status, StatusCode::OK);
+        entries.as_array().unwrap().len()
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn history_is_trimmed(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut state = Arc::into_inner(test_state(db, log_dir.path())).unwrap();
+        state.history_keep = Some(3);
+        let state = Arc::new(state);
+        for i in 0..5 {
+            let req = push_result("foo", "amd64", i % 2 == 0, b"log").await;
+            let (status, body) = send(state.clone(), req).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        // another package keeps its own history
+        let req = push_result("foo", "arm64", true, b"log").await;
+        send(state.clone(), req).await;
+        assert_eq!(history_len(state.clone()).await, 3);
+
+        // a push keeping the stored log is trimmed too
+        let req = push(&[
+            ("package", b"foo"),
+            ("arch", b"amd64"),
+            ("success", b"true"),
+            ("keep_log", b"true"),
+        ]);
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (_, entries) = get_json(state.clone(), "/history?name=foo&arch=amd64").await;
+        let successes: Vec<_> = entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["success"].as_bool().unwrap())
+            .collect();
+        // the newest three of false, true, false, true, true
+        assert_eq!(successes, [false, true, true]);
+
+        let (_, entries) = get_json(state, "/history?name=foo&arch=arm64").await;
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn batched_history_is_trimmed(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut state = Arc::into_inner(test_state(db.clone(), log_dir.path())).unwrap();
+        state.history_keep = Some(2);
+        state.batcher = Some(WriteBatcher::spawn(
+            db,
+            Duration::from_millis(50),
+            state.history_keep,
+        ));
+        let state = Arc::new(state);
+
+        // pushed together, so some of them land in one batch
+        let pushes: Vec<_> = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let req = push_result("foo", "amd64", true, b"log").await;
+                    send(state, req).await
+                })
+            })
+            .collect();
+        for push in pushes {
+            let (status, body) = push.await.unwrap();
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        assert_eq!(history_len(state).await, 2);
+    }
+
+    #[test]
+    fn upload_sizes_are_bucketed() {
+        let recorder = metrics_builder().unwrap().build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || record_upload_sizes(3000, 2_000_000));
+
+        let rendered = handle.render();
+        assert!(
+            rendered.contains("reworkit_upload_compressed_bytes_bucket{le=\"4096\"} 1"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("reworkit_upload_compressed_bytes_bucket{le=\"1024\"} 0"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("reworkit_upload_log_bytes_bucket{le=\"4194304\"} 1"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("reworkit_upload_log_bytes_sum 2000000"),
+            "{rendered}"
+        );
+    }
+
+    const WORKER_KEY: [u8; 32] = [7; 32];
+
+    fn signing_state(db: PgPool, log_dir: &Path) -> Arc<AppState> {
+        let mut state = Arc::into_inner(test_state(db, log_dir)).unwrap();
+        state.worker_keys = vec![WorkerKey {
+            name: "builder".to_string(),
+            key: ed25519_dalek::SigningKey::from_bytes(&WORKER_KEY).verifying_key(),
+        }];
+        Arc::new(state)
+    }
+
+    fn sha256(x: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(x))
+    }
+
+    /// A push of `fields` signed with `WORKER_KEY` over `signed`
+    fn signed_push(fields: &[(&str, &[u8])], signed: &signature::Signed) -> Request {
+        use ed25519_dalek::Signer;
+
+        let signature = ed25519_dalek::SigningKey::from_bytes(&WORKER_KEY).sign(&signed.payload());
+        let mut req = push(fields);
+        req.headers_mut().insert(
+            signature::SIGNATURE,
+            HeaderValue::from_str(&hex::encode(signature.to_bytes())).unwrap(),
+        );
+        req
+    }
+
+    /// Log, stdout and stderr of a build, compressed
+    async fn build_output() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        (
+            gzip(b"out\nerr\n").await,
+            gzip(b"out\n").await,
+            gzip(b"err\n").await,
+        )
+    }
+
+    fn signed_fields<'a>(log: &'a str, stdout: &'a str, stderr: &'a str) -> signature::Signed<'a> {
+        signature::Signed {
+            package: "foo",
+            arch: "amd64",
+            success: "true",
+            expected_failure: false,
+            tree_commit: None,
+            artifacts: None,
+            log_sha256: Some(log),
+            stdout_sha256: Some(stdout),
+            stderr_sha256: Some(stderr),
+            exit_code: None,
+            commit_subject: None,
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn signed_push_is_verified(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = signing_state(db, log_dir.path());
+        let (log, stdout, stderr) = build_output().await;
+        let hashes = [sha256(&log), sha256(&stdout), sha256(&stderr)];
+
+        let req = signed_push(
+            &[
+                ("package", b"foo"),
+                ("arch", b"amd64"),
+                ("success", b"true"),
+                ("log", &log),
+                ("stdout", &stdout),
+                ("stderr", &stderr),
+            ],
+            &signed_fields(&hashes[0], &hashes[1], &hashes[2]),
+        );
+        let (status, body) = send(state.clone(), req).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (_, results) = get_json(state, "/get?name=foo").await;
+        assert_eq!(results[0]["verified"], true);
+    }
+
+    #[tokio::test]
+    async fn swapped_streams_are_not_verified() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = signing_state(no_db(), log_dir.path());
+        let (log, stdout, stderr) = build_output().await;
+        let hashes = [sha256(&log), sha256(&stdout), sha256(&stderr)];
+
+        // signed as built, sent with stdout and stderr the other way round
+        let req = signed_push(
+            &[
+                ("package", b"foo"),
+                ("arch", b"amd64"),
+                ("success", b"true"),
+                ("log", &log),
+                ("stdout", &stderr),
+                ("stderr", &stdout),
+            ],
+            &signed_fields(&hashes[0], &hashes[1], &hashes[2]),
+        );
+        let (status, body) = send(state, req).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, "invalid signature");
+    }
+
+    #[tokio::test]
+    async fn altered_exit_code_is_not_verified() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = signing_state(no_db(), log_dir.path());
+        let (log, stdout, stderr) = build_output().await;
+        let hashes = [sha256(&log), sha256(&stdout), sha256(&stderr)];
+        let mut signed = signed_fields(&hashes[0], &hashes[1], &hashes[2]);
+        signed.exit_code = Some("1");
+
+        let req = signed_push(
+            &[
+                ("package", b"foo"),
+                ("arch", b"amd64"),
+                ("success", b"true"),
+                ("exit_code", b"0"),
+                ("log", &log),
+                ("stdout", &stdout),
+                ("stderr", &stderr),
+            ],
+            &signed,
+        );
+        let (status, body) = send(state, req).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, "invalid signature");
+    }
+
+    #[tokio::test]
+    async fn altered_commit_subject_is_not_verified() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = signing_state(no_db(), log_dir.path());
+        let (log, stdout, stderr) = build_output().await;
+        let hashes = [sha256(&log), sha256(&stdout), sha256(&stderr)];
+        let mut signed = signed_fields(&hashes[0], &hashes[1], &hashes[2]);
+        signed.commit_subject = Some("foo: update to 1.0");
+
+        let req = signed_push(
+            &[
+                ("package", b"foo"),
+                ("arch", b"amd64"),
+                ("success", b"true"),
+                ("commit_subject", b"foo: update to 6.6.6"),
+                ("log", &log),
+                ("stdout", &stdout),
+                ("stderr", &stderr),
+            ],
+            &signed,
+        );
+        let (status, body) = send(state, req).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, "invalid signature");
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn consecutive_failures_are_counted(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        for (arch, success) in [
+            ("amd64", false),
+            ("amd64", true),
+            ("amd64", false),
+            ("amd64", false),
+            ("arm64", true),
+            ("riscv64", false),
+        ] {
+            let req = push_result("foo", arch, success, b"log").await;
+            let (status, body) = send(state.clone(), req).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+
+        let (status, results) = get_json(state.clone(), "/get?name=foo").await;
+        assert_eq!(status, StatusCode::OK);
+        let failures: BTreeMap<_, _> = results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| {
+                (
+                    x["arch"].as_str().unwrap().to_string(),
+                    x["consecutive_failures"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            failures,
+            BTreeMap::from([
+                ("amd64".to_string(), 2),
+                ("arm64".to_string(), 0),
+                ("riscv64".to_string(), 1),
+            ])
+        );
+
+        let (_, results) = get_json(state, "/get?name=foo&arch=amd64").await;
+        assert_eq!(results[0]["consecutive_failures"], 2);
+        assert_eq!(results[0]["success"], false);
+    }
+
+    #[sqlx::test(migrations = "./migrations")]
+    #[ignore = "needs a Postgres server in DATABASE_URL"]
+    async fn flaky_packages_are_ranked(db: PgPool) {
+        let log_dir = tempfile::tempdir().unwrap();
+        let state = test_state(db, log_dir.path());
+        for (package, results) in [("flaky", "10101"), ("fixed", "00011"), ("broken", "00000")] {
+            for result in results.chars() {
+                let req = push_result(package, "amd64", result == '1', b"log").await;
+                let (status, body) = send(state.clone(), req).await;
+                assert_eq!(status, StatusCode::OK, "{body}");
+            }
+        }
+
+        let (status, flaky) = get_json(state.clone(), "/flaky").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            flaky,
+            serde_json::json!([
+                { "name": "flaky", "arch": "amd64", "flips": 4, "runs": 5 },
+                { "name": "fixed", "arch": "amd64", "flips": 1, "runs": 5 },
+            ])
+        );
+
+        // only the last three results, 101 and 011
+        let (_, flaky) = get_json(state.clone(), "/flaky?window=3").await;
+        assert_eq!(flaky[0]["flips"], 2);
+        assert_eq!(flaky[1]["flips"], 1);
+        assert_eq!(flaky[1]["runs"], 3);
+
+        let (_, flaky) = get_json(state, "/flaky?arch=arm64").await;
+        assert_eq!(flaky, serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn decompression_bomb_is_rejected() {
+        let log_dir = tempfile::tempdir().unwrap();
+        let mut state = Arc::into_inner(test_state(no_db(), log_dir.path())).unwrap();
+        state.log_max_bytes = Some(64 * 1024);
+        // a few hundred bytes of upload
+        let req = push_result("foo", "amd64", true, &vec![0; 1024 * 1024]).await;
+
+        let (status, body) = send(Arc::new(state), req).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body.contains("log decompresses to more than 65536 bytes"),
+            "{body}"
+        );
+        assert_eq!(std::fs::read_dir(log_dir.path()).unwrap().count(), 0);
+    }
 }
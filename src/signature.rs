@@ -0,0 +1,103 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+
+/// Header a worker puts the hex encoded ed25519 signature of its push in
+pub const SIGNATURE: &str = "x-reworkit-signature";
+
+/// Fields of a push as sent, the signature covers these
+pub struct Signed<'a> {
+    pub package: &'a str,
+    pub arch: &'a str,
+    pub success: &'a str,
+    pub expected_failure: bool,
+    pub tree_commit: Option<&'a str>,
+    pub artifacts: Option<&'a str>,
+    /// Hex encoded SHA-256 of the log as uploaded
+    pub log_sha256: Option<&'a str>,
+    /// Hex encoded SHA-256 of the stdout and stderr parts as uploaded
+    pub stdout_sha256: Option<&'a str>,
+    pub stderr_sha256: Option<&'a str>,
+    pub exit_code: Option<&'a str>,
+    pub commit_subject: Option<&'a str>,
+}
+
+impl Signed<'_> {
+    /// The bytes that are signed, must stay in sync with `signed_payload` in the worker
+    pub fn payload(&self) -> Vec<u8> {
+        [
+            "reworkit-v2",
+            self.package,
+            self.arch,
+            self.success,
+            if self.expected_failure {
+                "true"
+            } else {
+                "false"
+            },
+            self.tree_commit.unwrap_or_default(),
+            self.artifacts.unwrap_or_default(),
+            self.log_sha256.unwrap_or_default(),
+            self.stdout_sha256.unwrap_or_default(),
+            self.stderr_sha256.unwrap_or_default(),
+            self.exit_code.unwrap_or_default(),
+            // last, so a newline in it cannot be taken for the end of another field
+            self.commit_subject.unwrap_or_default(),
+        ]
+        .join("\n")
+        .into_bytes()
+    }
+}
+
+/// A worker allowed to sign pushes
+pub struct WorkerKey {
+    /// How the worker is named in the audit trail
+    pub name: String,
+    pub key: VerifyingKey,
+}
+
+/// Parse a file of hex encoded ed25519 public keys, one per line, `#` starts a comment
+///
+/// A key may be followed by the worker's name, otherwise the key itself names it.
+pub fn load_keys(path: &Path) -> Result<Vec<WorkerKey>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read worker keys {}", path.display()))?;
+
+    let mut keys = vec![];
+    for (no, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (key, name) = line.split_once(char::is_whitespace).unwrap_or((line, line));
+        let key = hex::decode(key)
+            .ok()
+            .and_then(|x| <[u8; 32]>::try_from(x).ok())
+            .and_then(|x| VerifyingKey::from_bytes(&x).ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "{}:{}: not a hex encoded ed25519 public key",
+                    path.display(),
+                    no + 1
+                )
+            })?;
+        keys.push(WorkerKey {
+            name: name.trim().to_string(),
+            key,
+        });
+    }
+
+    Ok(keys)
+}
+
+/// The worker whose key `signature` is a valid signature of `payload` by, if any
+pub fn verify<'a>(keys: &'a [WorkerKey], payload: &[u8], signature: &str) -> Option<&'a WorkerKey> {
+    let signature = hex::decode(signature.trim())
+        .ok()
+        .and_then(|x| Signature::from_slice(&x).ok())?;
+
+    keys.iter()
+        .find(|x| x.key.verify_strict(payload, &signature).is_ok())
+}
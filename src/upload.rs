@@ -0,0 +1,178 @@
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Result;
+use axum::extract::multipart::Field;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    io::{self, AsyncWrite, AsyncWriteExt},
+};
+use uuid::Uuid;
+
+use crate::{codec::Codec, LogCorrupt, LogTooLarge};
+
+/// How many leading bytes of an upload are kept to check its format
+const HEAD_LEN: usize = 8;
+
+/// A log field decompressed into the log directory as it was received
+///
+/// The temporary files are removed on drop, unless `write_log` moved them into place.
+pub struct Upload {
+    /// The decompressed log
+    pub tmp: PathBuf,
+    /// The log as uploaded, kept with `REWORKIT_LOG_STORE_BOTH`
+    pub compressed_tmp: Option<PathBuf>,
+    /// Codec the log was decompressed with
+    pub codec: Codec,
+    /// Leading bytes of the upload
+    pub head: Vec<u8>,
+    pub compressed_bytes: u64,
+    /// Hex encoded SHA-256 of the upload, as covered by a signature
+    pub sha256: String,
+    /// The decompressed size, or why the log could not be decompressed
+    pub decompressed: Result<u64>,
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.tmp).ok();
+        if let Some(copy) = &self.compressed_tmp {
+            std::fs::remove_file(copy).ok();
+        }
+    }
+}
+
+/// Stream the log field chunk by chunk through the decoder into a temporary file
+///
+/// A log that fails to decompress is still read to the end, so the rest of the
+/// form can be checked and reported on first.
+pub async fn receive(
+    mut field: Field<'_>,
+    codec: Codec,
+    log_dir: &Path,
+    max_bytes: Option<u64>,
+    store_both: bool,
+) -> Result<Upload> {
+    fs::create_dir_all(log_dir).await?;
+
+    let id = Uuid::new_v4();
+    let mut upload = Upload {
+        tmp: log_dir.join(format!(".upload-{id}.tmp")),
+        compressed_tmp: store_both
+            .then(|| log_dir.join(format!(".upload-{id}.{}.tmp", codec.extension()))),
+        codec,
+        head: vec![],
+        compressed_bytes: 0,
+        sha256: String::new(),
+        decompressed: Ok(0),
+    };
+
+    let mut copy = match &upload.compressed_tmp {
+        Some(path) => Some(fs::File::create(path).await?),
+        None => None,
+    };
+    let mut decoder = codec.decoder(LimitedWriter {
+        inner: fs::File::create(&upload.tmp).await?,
+        written: 0,
+        max_bytes,
+    });
+    let mut hasher = Sha256::new();
+    let mut failed = None;
+
+    while let Some(chunk) = field.chunk().await? {
+        hasher.update(&chunk);
+        upload.compressed_bytes += chunk.len() as u64;
+        let wanted = HEAD_LEN.saturating_sub(upload.head.len());
+        upload.head.extend(chunk.iter().take(wanted));
+
+        if let Some(copy) = &mut copy {
+            copy.write_all(&chunk).await?;
+        }
+        if failed.is_none() {
+            failed = decoder.write_all(&chunk).await.err();
+        }
+    }
+    // the decoders check the trailer (gzip CRC32 and ISIZE, zstd checksum) on shutdown
+    if failed.is_none() {
+        failed = decoder.shutdown().await.err();
+    }
+    drop(decoder);
+    if let Some(mut copy) = copy {
+        copy.flush().await?;
+    }
+
+    upload.sha256 = format!("{:x}", hasher.finalize());
+    upload.decompressed = match failed {
+        None => Ok(fs::metadata(&upload.tmp).await?.len()),
+        Some(e) => Err(classify(e)),
+    };
+
+    Ok(upload)
+}
+
+/// Tell the errors of `LimitedWriter` apart from the decoder rejecting the stream
+fn classify(e: io::Error) -> anyhow::Error {
+    if let Some(too_large) = e.get_ref().and_then(|x| x.downcast_ref::<LogTooLarge>()) {
+        return LogTooLarge(too_large.0).into();
+    }
+    if e.get_ref().is_some_and(|x| x.is::<WriteFailed>()) {
+        return e.into();
+    }
+
+    // invalid data, or the stream ending before its trailer
+    LogCorrupt(e).into()
+}
+
+/// Writing the decompressed log to disk failed
+#[derive(Debug)]
+struct WriteFailed(io::Error);
+
+impl std::fmt::Display for WriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for WriteFailed {}
+
+/// Writes through to `inner`, failing once more than `max_bytes` were written
+struct LimitedWriter<W> {
+    inner: W,
+    written: u64,
+    max_bytes: Option<u64>,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LimitedWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(max) = self.max_bytes {
+            if self.written + buf.len() as u64 > max {
+                return Poll::Ready(Err(io::Error::other(LogTooLarge(max))));
+            }
+        }
+
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf)).map_err(wrap)?;
+        self.written += n as u64;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(wrap)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx).map_err(wrap)
+    }
+}
+
+fn wrap(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), WriteFailed(e))
+}
@@ -0,0 +1,74 @@
+use std::{io::IsTerminal, time::Duration};
+
+use anyhow::Result;
+use tracing::warn;
+
+use crate::Package;
+
+/// Poll `/recent` and print every result that comes in, until interrupted
+pub async fn watch(url: &str, interval: Duration) -> Result<()> {
+    let client = reqwest::Client::builder().user_agent("reworkit").build()?;
+    let color = std::io::stdout().is_terminal();
+
+    // results already there when watching starts are not printed
+    let mut last = recent(&client, url).await?.first().map(|x| x.finished_at);
+    println!("Watching {url} for new results");
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let packages = match recent(&client, url).await {
+            Ok(packages) => packages,
+            Err(e) => {
+                warn!("Failed to poll {url}/recent: {}", e);
+                continue;
+            }
+        };
+
+        // newest first, print oldest first
+        let new: Vec<_> = packages
+            .into_iter()
+            .take_while(|x| last.is_none_or(|last| x.finished_at > last))
+            .collect();
+        if let Some(newest) = new.first() {
+            last = Some(newest.finished_at);
+        }
+        for pkg in new.iter().rev() {
+            print_result(pkg, color);
+        }
+    }
+}
+
+async fn recent(client: &reqwest::Client, url: &str) -> Result<Vec<Package>> {
+    let body = client
+        .get(format!("{url}/recent"))
+        .query(&[("limit", "1000")])
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn print_result(pkg: &Package, color: bool) {
+    let (status, code) = match (pkg.success, pkg.expected_failure) {
+        (true, _) => ("success", "32"),
+        (false, true) => ("expected failure", "33"),
+        (false, false) => ("failed", "31"),
+    };
+    let status = if color {
+        format!("\x1b[{code}m{status}\x1b[0m")
+    } else {
+        status.to_string()
+    };
+
+    println!(
+        "{} {}/{} {}",
+        pkg.finished_at.format("%Y-%m-%d %H:%M:%S"),
+        pkg.name,
+        pkg.arch,
+        status
+    );
+}
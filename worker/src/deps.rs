@@ -0,0 +1,354 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+};
+
+use tracing::{info, warn};
+use walkdir::WalkDir;
+
+/// Order `queue` so packages come after their build dependencies in it, keeping the
+/// queue's order otherwise
+///
+/// Returns the dependencies of each package that come before it, the ones a build has to
+/// wait for. A dependency cycle is broken at the package queued first.
+pub fn order(
+    tree_dir: &Path,
+    queue: Vec<String>,
+    arch: &str,
+) -> (Vec<String>, HashMap<String, Vec<String>>) {
+    let deps = read_deps(tree_dir, &queue, arch);
+    let index: HashMap<&str, usize> = queue
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.as_str(), i))
+        .collect();
+
+    // dependencies of each queued package by position, only those queued too
+    let edges: Vec<BTreeSet<usize>> = queue
+        .iter()
+        .enumerate()
+        .map(|(i, pkg)| {
+            deps.get(pkg)
+                .into_iter()
+                .flatten()
+                .filter_map(|x| index.get(x.as_str()).copied())
+                .filter(|x| *x != i)
+                .collect()
+        })
+        .collect();
+
+    let mut remaining: BTreeSet<usize> = (0..queue.len()).collect();
+    let mut ordered = vec![];
+    while let Some(&first) = remaining.iter().next() {
+        let ready = remaining
+            .iter()
+            .copied()
+            .find(|x| edges[*x].iter().all(|dep| !remaining.contains(dep)));
+
+        let next = match ready {
+            Some(next) => next,
+            None => {
+                let cycle = find_cycle(first, &edges, &remaining);
+                let names: Vec<_> = cycle.iter().map(|x| queue[*x].as_str()).collect();
+                let next = *cycle.iter().min().unwrap_or(&first);
+                warn!(
+                    "Build dependency cycle between {}, building {} first",
+                    names.join(" -> "),
+                    queue[next]
+                );
+                next
+            }
+        };
+
+        remaining.remove(&next);
+        ordered.push(next);
+    }
+
+    let position: HashMap<usize, usize> = ordered
+        .iter()
+        .enumerate()
+        .map(|(pos, x)| (*x, pos))
+        .collect();
+    let mut waits = HashMap::new();
+    for &i in &ordered {
+        let before: Vec<String> = edges[i]
+            .iter()
+            .filter(|dep| position[*dep] < position[&i])
+            .map(|dep| queue[*dep].clone())
+            .collect();
+        if !before.is_empty() {
+            waits.insert(queue[i].clone(), before);
+        }
+    }
+
+    let moved = edges
+        .iter()
+        .enumerate()
+        .filter(|(i, deps)| deps.iter().any(|x| x > i))
+        .count();
+    if moved > 0 {
+        info!("{moved} packages were queued before their dependencies, building those first");
+    }
+
+    (
+        ordered.into_iter().map(|x| queue[x].clone()).collect(),
+        waits,
+    )
+}
+
+/// Follow the dependencies from `start` among `remaining` until one repeats, every
+/// package left there waits on another so this always ends in a cycle
+fn find_cycle(start: usize, edges: &[BTreeSet<usize>], remaining: &BTreeSet<usize>) -> Vec<usize> {
+    let mut path = vec![start];
+    loop {
+        let last = path[path.len() - 1];
+        let Some(&next) = edges[last].iter().find(|x| remaining.contains(x)) else {
+            return path;
+        };
+        if let Some(pos) = path.iter().position(|x| *x == next) {
+            return path.split_off(pos);
+        }
+        path.push(next);
+    }
+}
+
+/// Build dependencies of each package in `pkgs`, by the names of the packages in the tree
+///
+/// These are PKGDEP and BUILDDEP of every `defines` of the package, or their `__<ARCH>`
+/// variants where set. Dependencies are matched to packages by the PKGNAMEs they define.
+fn read_deps(tree_dir: &Path, pkgs: &[String], arch: &str) -> HashMap<String, BTreeSet<String>> {
+    let wanted: BTreeSet<&str> = pkgs.iter().map(|x| x.as_str()).collect();
+    let arch = arch.to_uppercase();
+
+    let mut provides = HashMap::new();
+    let mut needs: HashMap<String, BTreeSet<String>> = HashMap::new();
+    // packages live at <section>/<pkg>, see list_packages
+    for entry in WalkDir::new(tree_dir)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .flatten()
+    {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !entry.file_type().is_dir()
+            || !wanted.contains(name.as_str())
+            || needs.contains_key(&name)
+        {
+            continue;
+        }
+
+        let mut deps = BTreeSet::new();
+        provides.insert(name.clone(), name.clone());
+        for defines in defines_files(entry.path()) {
+            let Ok(content) = std::fs::read_to_string(&defines) else {
+                continue;
+            };
+            let vars = parse_defines(&content);
+            if let Some(pkgname) = vars.get("PKGNAME") {
+                provides.insert(pkgname.clone(), name.clone());
+            }
+            for var in ["PKGDEP", "BUILDDEP"] {
+                let value = vars
+                    .get(&format!("{var}__{arch}"))
+                    .or_else(|| vars.get(var));
+                deps.extend(value.into_iter().flat_map(|x| dep_names(x)));
+            }
+        }
+        needs.insert(name, deps);
+    }
+
+    needs
+        .into_iter()
+        .map(|(pkg, deps)| {
+            let deps = deps
+                .iter()
+                .filter_map(|x| provides.get(x).cloned())
+                .filter(|x| *x != pkg)
+                .collect();
+            (pkg, deps)
+        })
+        .collect()
+}
+
+/// `autobuild/defines`, and `<n>-<name>/defines` of every package a split package defines
+fn defines_files(dir: &Path) -> Vec<std::path::PathBuf> {
+    let mut files = vec![dir.join("autobuild/defines")];
+    files.extend(
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|x| x.path().join("defines"))
+            .filter(|x| x.is_file()),
+    );
+
+    files
+}
+
+/// Package names of a dependency list, without their version constraints
+fn dep_names(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split_whitespace()
+        .filter(|x| !x.starts_with('$'))
+        .map(|x| {
+            let end = x.find(['<', '>', '=']).unwrap_or(x.len());
+            x[..end].to_string()
+        })
+        .filter(|x| !x.is_empty())
+}
+
+/// The `NAME=value` assignments of a defines file, quoted values may span lines
+fn parse_defines(content: &str) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    let mut lines = content.lines();
+
+    while let Some(line) = lines.next() {
+        let Some((name, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        if name.is_empty() || !name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') {
+            continue;
+        }
+
+        let mut value = value.to_string();
+        if let Some(quote) = value.chars().next().filter(|x| *x == '"' || *x == '\'') {
+            value.remove(0);
+            while !value.contains(quote) {
+                match lines.next() {
+                    Some(next) => {
+                        value.push('\n');
+                        value.push_str(next);
+                    }
+                    None => break,
+                }
+            }
+            value.truncate(value.find(quote).unwrap_or(value.len()));
+        }
+        // line continuations are whitespace like any other
+        vars.insert(name.to_string(), value.replace('\\', " "));
+    }
+
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tree with these files, by path below it
+    fn tree(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        dir
+    }
+
+    fn queue(pkgs: &[&str]) -> Vec<String> {
+        pkgs.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn waits(pairs: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        pairs
+            .iter()
+            .map(|(pkg, deps)| (pkg.to_string(), queue(deps)))
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let tree = tree(&[
+            ("app-a/a/autobuild/defines", "PKGNAME=a\nBUILDDEP=\"b\"\n"),
+            (
+                "app-a/b/autobuild/defines",
+                "PKGNAME=b\nPKGDEP=\"c>=1.0 glibc\"\n",
+            ),
+            ("app-b/c/autobuild/defines", "PKGNAME=c\n"),
+            (
+                "app-b/d/autobuild/defines",
+                "PKGNAME=d\nPKGDEP=\"$PKGDEP_EXTRA\"\n",
+            ),
+        ]);
+
+        let (order, waits_on) = order(tree.path(), queue(&["a", "d", "b", "c"]), "amd64");
+        // d does not depend on anything queued and keeps its place among the rest
+        assert_eq!(order, queue(&["d", "c", "b", "a"]));
+        assert_eq!(waits_on, waits(&[("a", &["b"]), ("b", &["c"])]));
+    }
+
+    #[test]
+    fn cycle_is_broken_at_the_package_queued_first() {
+        let tree = tree(&[
+            ("app-a/x/autobuild/defines", "PKGNAME=x\nBUILDDEP=\"y\"\n"),
+            ("app-a/y/autobuild/defines", "PKGNAME=y\nPKGDEP=\"x\"\n"),
+            ("app-a/z/autobuild/defines", "PKGNAME=z\n"),
+        ]);
+
+        let (order, waits_on) = order(tree.path(), queue(&["x", "y", "z"]), "amd64");
+        assert_eq!(order, queue(&["z", "x", "y"]));
+        // x is built before its dependency y, so it waits on nothing
+        assert_eq!(waits_on, waits(&[("y", &["x"])]));
+
+        let edges = vec![
+            BTreeSet::from([1]),
+            BTreeSet::from([2]),
+            BTreeSet::from([1]),
+        ];
+        let remaining = BTreeSet::from([0, 1, 2]);
+        assert_eq!(find_cycle(0, &edges, &remaining), vec![1, 2]);
+    }
+
+    #[test]
+    fn split_packages_provide_every_name() {
+        let tree = tree(&[
+            ("lang-a/s/01-s-core/defines", "PKGNAME=s-core\n"),
+            ("lang-a/s/02-s-doc/defines", "PKGNAME=s-doc\nPKGDEP=\"t\"\n"),
+            ("lang-a/t/autobuild/defines", "PKGNAME=t\n"),
+            (
+                "lang-a/u/autobuild/defines",
+                "PKGNAME=u\nBUILDDEP=\"s-core\"\n",
+            ),
+        ]);
+
+        let (order, waits_on) = order(tree.path(), queue(&["u", "s", "t"]), "amd64");
+        assert_eq!(order, queue(&["t", "s", "u"]));
+        assert_eq!(waits_on, waits(&[("s", &["t"]), ("u", &["s"])]));
+    }
+
+    #[test]
+    fn arch_variables_override() {
+        let tree = tree(&[
+            (
+                "app-a/p/autobuild/defines",
+                "PKGNAME=p\nPKGDEP=\"q\"\nPKGDEP__AMD64=\"r\"\n",
+            ),
+            ("app-a/q/autobuild/defines", "PKGNAME=q\n"),
+            ("app-a/r/autobuild/defines", "PKGNAME=r\n"),
+        ]);
+
+        let (_, waits_on) = order(tree.path(), queue(&["p", "q", "r"]), "amd64");
+        assert_eq!(waits_on, waits(&[("p", &["r"])]));
+        let (_, waits_on) = order(tree.path(), queue(&["p", "q", "r"]), "arm64");
+        assert_eq!(waits_on, waits(&[("p", &["q"])]));
+    }
+
+    #[test]
+    fn defines_values() {
+        let vars = parse_defines(
+            "# comment\nPKGNAME=foo\nPKGDEP=\"bar baz>=2 \\\n    qux\"\nBUILDDEP='a\nb'\n  PKGDES=\"x = y\"\n",
+        );
+        assert_eq!(vars["PKGNAME"], "foo");
+        assert_eq!(
+            dep_names(&vars["PKGDEP"]).collect::<Vec<_>>(),
+            queue(&["bar", "baz", "qux"])
+        );
+        assert_eq!(
+            dep_names(&vars["BUILDDEP"]).collect::<Vec<_>>(),
+            queue(&["a", "b"])
+        );
+        assert_eq!(vars["PKGDES"], "x = y");
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::{Component, Path};
+
+/// Directories of an AOSC OS tree that are not packages, ignored on top of `--ignore`
+pub const DEFAULT_IGNORE: [&str; 4] = [".git*", "*/.git*", "groups", "assets"];
+
+/// Whether `relative`, a path below the tree, or a directory it is in matches one of
+/// `patterns`
+pub fn is_ignored(patterns: &[String], relative: &Path) -> bool {
+    let mut prefix = String::new();
+    for component in relative.components() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(&name.to_string_lossy());
+
+        if patterns.iter().any(|x| matches(x, &prefix)) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Match a glob pattern, `*` matches any characters and `?` one, neither matches a `/`
+fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*` if what follows it does not match
+    let mut star = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if p < pattern.len()
+            && (pattern[p] == text[t] || (pattern[p] == '?' && text[t] != '/'))
+        {
+            p += 1;
+            t += 1;
+        } else if let Some((after, from)) = star {
+            // an earlier `*` could not get past this `/` either
+            if text[from] == '/' {
+                return false;
+            }
+            star = Some((after, from + 1));
+            p = after;
+            t = from + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn globs() {
+        assert!(matches("groups", "groups"));
+        assert!(!matches("groups", "groups2"));
+        assert!(matches("extra-*", "extra-scripts"));
+        assert!(matches("extra-*", "extra-"));
+        assert!(matches("*-base", "core-base"));
+        assert!(matches("app-?", "app-x"));
+        assert!(!matches("app-?", "app-"));
+        assert!(matches("*/.git*", "app-web/.gitkeep"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn star_stays_in_a_component() {
+        assert!(!matches("*", "app-web/foo"));
+        assert!(!matches("app*foo", "app-web/foo"));
+        assert!(matches("*/foo", "app-web/foo"));
+        assert!(!matches("?", "/"));
+        assert!(!matches("app-web?foo", "app-web/foo"));
+    }
+
+    #[test]
+    fn directories_hide_what_is_below() {
+        let patterns = patterns(&["extra-*", "app-web/foo"]);
+        assert!(is_ignored(&patterns, Path::new("extra-scripts/bar")));
+        assert!(is_ignored(&patterns, Path::new("app-web/foo")));
+        assert!(is_ignored(&patterns, Path::new("app-web/foo/autobuild")));
+        assert!(!is_ignored(&patterns, Path::new("app-web/foobar")));
+        assert!(!is_ignored(&patterns, Path::new("app-web")));
+    }
+
+    #[test]
+    fn defaults() {
+        let defaults = patterns(&DEFAULT_IGNORE);
+        for path in [
+            ".git/objects",
+            ".github/workflows",
+            "groups/x",
+            "assets/y",
+            "app-web/.gitkeep",
+        ] {
+            assert!(
+                is_ignored(&defaults, Path::new(path)),
+                "{path} is not ignored"
+            );
+        }
+        for path in ["app-web/foo", "app-web/groups", "core-base/git"] {
+            assert!(!is_ignored(&defaults, Path::new(path)), "{path} is ignored");
+        }
+    }
+}
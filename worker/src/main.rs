@@ -1,37 +1,242 @@
-use anyhow::{ensure, Result};
-use async_compression::tokio::write::GzipEncoder;
-use clap::Parser;
+mod deps;
+mod ignore;
+use anyhow::{ensure, Context, Result};
+use async_compression::{
+    tokio::write::{GzipEncoder, ZstdEncoder},
+    Level,
+};
+use bytes::Bytes;
+use clap::{ArgAction, Parser, ValueEnum};
+use ed25519_dalek::{Signer, SigningKey};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use ignore::{is_ignored, DEFAULT_IGNORE};
 use reqwest::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
     multipart::{self, Part},
-    Client,
+    Client, StatusCode,
 };
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
 use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::Display,
+    os::unix::process::ExitStatusExt,
     path::{Path, PathBuf},
-    sync::Arc, time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+    task::spawn_blocking,
 };
-use tokio::{io::AsyncWriteExt, process::Command, task::spawn_blocking};
-use tracing::{error, info, level_filters::LevelFilter};
+use tracing::{error, info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use uuid::Uuid;
 use walkdir::WalkDir;
 
+const EXIT_BUILD_FAILED: i32 = 1;
+const EXIT_INFRA_ERROR: i32 = 2;
+const EXIT_MISCONFIGURED: i32 = 3;
+
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Exit codes with --once:
+  0  every package built successfully
+  1  at least one build failed, not counting --expected-failures
+  2  infrastructure error (git, ciel or pushing to the server failed)
+  3  misconfiguration
+
+On SIGTERM or SIGINT the builds in progress are finished and pushed before exiting. Under
+systemd, set KillMode=mixed so the builds do not get the signal as well.
+
+Run `worker push --help` to push an existing log without building."
+)]
 struct Args {
     /// CIEL! workspace path
     #[clap(short = 'd', long, env = "REWORKIT_CIEL_WORKSPACE")]
     workspace: PathBuf,
     /// Instance architecture
+    ///
+    /// One per worker process, the arch is that of the workspace's OS tree. Run a worker per
+    /// workspace to build several arches on one host, and pause one with --disable-file.
     #[clap(short, long, env = "REWORKIT_ARCH")]
     arch: String,
-    /// Instance name (default: main)
-    #[clap(short, long, default_value = "main", env = "REWORKIT_CIEL_INSTANCE")]
-    name: String,
+    /// Instance name, comma-separated candidates are tried in order (default: main)
+    #[clap(
+        short,
+        long,
+        default_value = "main",
+        env = "REWORKIT_CIEL_INSTANCE",
+        value_delimiter = ','
+    )]
+    name: Vec<String>,
     /// ReworkIt! server url
     #[clap(short, long, env = "REWORKIT_URL")]
     url: String,
     #[clap(short, long, env = "REWORKIT_SECRET_TOKEN")]
     /// ReworkIt! secret token
     token: String,
+    /// Log compression level (default: picked by log size)
+    #[clap(long, env = "REWORKIT_COMPRESSION_LEVEL", value_parser = clap::value_parser!(i32).range(0..=9))]
+    compression_level: Option<i32>,
+    /// Compress logs with this instead of what the server asks for
+    #[clap(long, env = "REWORKIT_COMPRESSION", value_enum)]
+    compression: Option<Codec>,
+    /// Stop starting new builds once a cycle has run this long, the rest go first next cycle
+    #[clap(long, env = "REWORKIT_MAX_CYCLE_SECS")]
+    max_cycle_secs: Option<u64>,
+    /// Also record every result into a local SQLite database at this path
+    #[clap(long, env = "REWORKIT_SQLITE")]
+    sqlite: Option<PathBuf>,
+    /// Notify systemd of readiness and send watchdog pings while the build loop makes progress
+    #[clap(long, env = "REWORKIT_SD_NOTIFY")]
+    sd_notify: bool,
+    /// Build the tree at this commit or tag (detached) instead of pulling the branch HEAD
+    #[clap(long = "ref", env = "REWORKIT_REF")]
+    git_ref: Option<String>,
+    /// With --ref, check the previous branch out again once the cycle is done
+    #[clap(long, requires = "git_ref")]
+    restore_branch: bool,
+    /// File of `package = instance` lines, building those packages in their own instance
+    #[clap(long, env = "REWORKIT_INSTANCE_OVERRIDES")]
+    instance_overrides: Option<PathBuf>,
+    /// File listing packages expected to fail on this arch, one per line, their failures
+    /// are reported as expected
+    #[clap(long, env = "REWORKIT_EXPECTED_FAILURES")]
+    expected_failures: Option<PathBuf>,
+    /// Run builds under `nice` with this niceness
+    #[clap(long, env = "REWORKIT_NICE", allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+    nice: Option<i32>,
+    /// Run builds under `ionice` with this scheduling class
+    #[clap(long, env = "REWORKIT_IONICE", value_enum)]
+    ionice: Option<IoniceClass>,
+    /// Skip cycles while this file exists, to pause building this arch without a restart
+    #[clap(long, env = "REWORKIT_DISABLE_FILE")]
+    disable_file: Option<PathBuf>,
+    /// Run a single cycle and exit, see below for exit codes
+    #[clap(long)]
+    once: bool,
+    /// Build against the current OS state if `ciel update-os` fails instead of aborting the cycle
+    #[clap(long, env = "REWORKIT_CONTINUE_ON_UPDATE_FAILURE")]
+    continue_on_update_failure: bool,
+    /// Sign pushes with the hex encoded ed25519 secret key in this file
+    #[clap(long, env = "REWORKIT_SIGN_KEY")]
+    sign_key: Option<PathBuf>,
+    /// Append `package,duration_ms,status` to this CSV file as each build finishes
+    #[clap(long, env = "REWORKIT_TIMING_CSV")]
+    timing_csv: Option<PathBuf>,
+    /// Build packages the server has no result for on this arch first
+    #[clap(long, env = "REWORKIT_PRIORITIZE_MISSING")]
+    prioritize_missing: bool,
+    /// Only upload the log when the result differs from the one the server has
+    #[clap(long, env = "REWORKIT_LOGS_ON_CHANGE_ONLY")]
+    logs_on_change_only: bool,
+    /// Send the subject of the last commit touching each package's directory
+    #[clap(long, env = "REWORKIT_COMMIT_SUBJECT")]
+    commit_subject: bool,
+    /// Build up to this many packages at once, each in its own instance from --name, so
+    /// list at least as many there
+    #[clap(short = 'j', long, default_value_t = 1, env = "REWORKIT_JOBS", value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+    /// Build every package each cycle, not only those changed since the last cycle or since
+    /// their last successful build
+    #[clap(long, env = "REWORKIT_BUILD_ALL")]
+    all: bool,
+    /// Build every package as with --all every this many cycles, so results do not go
+    /// stale as the OS they are built against changes while the tree does not
+    #[clap(long, env = "REWORKIT_REBUILD_EVERY", value_parser = clap::value_parser!(u32).range(1..))]
+    rebuild_every: Option<u32>,
+    /// Also build packages the server already has a successful result for on this arch, those
+    /// are otherwise only built once changed since the tree commit of that result
+    #[clap(long, env = "REWORKIT_FORCE")]
+    force: bool,
+    /// Kill a build that runs longer than this and report it as failed
+    #[clap(long, default_value_t = 3 * 60 * 60, env = "REWORKIT_BUILD_TIMEOUT_SECS")]
+    build_timeout_secs: u64,
+    /// Attempts at pushing each result before giving up on it
+    #[clap(long, default_value_t = 3, env = "REWORKIT_PUSH_RETRIES", value_parser = clap::value_parser!(u32).range(1..))]
+    push_retries: u32,
+    /// Also upload stdout and stderr apart, so they can be read on their own
+    #[clap(long, env = "REWORKIT_SPLIT_STREAMS")]
+    split_streams: bool,
+    /// Run `ciel update-os` every cycle, even when no package changed and nothing is built
+    #[clap(long, env = "REWORKIT_ALWAYS_UPDATE_OS")]
+    always_update_os: bool,
+    /// Build only these packages, comma-separated or repeated, instead of every package in
+    /// the tree, whether changed or built successfully before or not
+    #[clap(short, long = "package", value_delimiter = ',')]
+    packages: Vec<String>,
+    /// Glob pattern of a directory in the tree that holds no packages, relative to TREE,
+    /// comma-separated or repeated; `*` and `?` stay within a path component. `.git*`,
+    /// `*/.git*`, `groups` and `assets` are always ignored
+    #[clap(long = "ignore", env = "REWORKIT_IGNORE", value_delimiter = ',')]
+    ignore: Vec<String>,
+    /// File of more --ignore patterns, one per line, `#` starts a comment
+    #[clap(long, env = "REWORKIT_IGNORE_FILE")]
+    ignore_file: Option<PathBuf>,
+}
+
+/// Push a result with an existing log file without building anything
+// parsed apart from Args, so the build options stay required there
+#[derive(Parser)]
+#[command(name = "worker push", bin_name = "worker push", version)]
+struct PushArgs {
+    /// Package name
+    #[arg(long)]
+    package: String,
+    /// Architecture the result is for
+    #[arg(long, env = "REWORKIT_ARCH")]
+    arch: String,
+    /// Whether the build succeeded
+    #[arg(long, action = ArgAction::Set)]
+    success: bool,
+    /// Uncompressed build log to upload
+    #[arg(long)]
+    log_file: PathBuf,
+    /// Commit of the tree the package was built from
+    #[arg(long)]
+    tree_commit: Option<String>,
+    /// ReworkIt! server url
+    #[arg(short, long, env = "REWORKIT_URL")]
+    url: String,
+    /// ReworkIt! secret token
+    #[arg(short, long, env = "REWORKIT_SECRET_TOKEN")]
+    token: String,
+    /// Sign the push with the hex encoded ed25519 secret key in this file
+    #[arg(long, env = "REWORKIT_SIGN_KEY")]
+    sign_key: Option<PathBuf>,
+    /// Log compression
+    #[arg(long, env = "REWORKIT_COMPRESSION", value_enum, default_value_t)]
+    compression: Codec,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum IoniceClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+impl IoniceClass {
+    /// Class number as understood by `ionice -c`
+    fn number(self) -> &'static str {
+        match self {
+            IoniceClass::Realtime => "1",
+            IoniceClass::BestEffort => "2",
+            IoniceClass::Idle => "3",
+        }
+    }
 }
 
 #[tokio::main]
@@ -66,158 +271,2106 @@ async fn main() -> Result<()> {
             .init();
     }
 
-    let Args {
-        workspace,
-        arch,
-        name,
-        url,
-        token,
-    } = Args::parse();
+    if std::env::args().nth(1).as_deref() == Some("push") {
+        let push = PushArgs::parse_from(std::env::args().skip(1));
+        let client = Client::builder().user_agent("reworkit").build()?;
+        return push_file(&client, &push).await;
+    }
+
+    let args = Args::try_parse().unwrap_or_else(|e| {
+        // --help and --version are not errors
+        let code = if e.use_stderr() {
+            EXIT_MISCONFIGURED
+        } else {
+            0
+        };
+        e.print().ok();
+        std::process::exit(code);
+    });
 
-    let tree_dir = Arc::new(workspace.join("TREE"));
     let client = Client::builder().user_agent("reworkit").build()?;
 
+    if args.sd_notify {
+        if let Some(timeout) = sd_notify::watchdog_enabled() {
+            tokio::spawn(watchdog(timeout));
+        }
+    }
+
+    tokio::spawn(stop_on_signal());
+    check_token(&client, &args.url, &args.token).await;
+
+    let saved = load_state(&args.workspace).await;
+    let mut state = WorkerState {
+        codec: args.compression.unwrap_or_default(),
+        deferred: saved.deferred,
+        built_commit: saved.built_commit,
+        cycles_since_rebuild: saved.cycles_since_rebuild,
+        ..Default::default()
+    };
+    if let Some(path) = &args.sign_key {
+        match load_sign_key(path).await {
+            Ok(key) => state.sign_key = Some(key),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(EXIT_MISCONFIGURED);
+            }
+        }
+    }
+    if let Some(path) = &args.sqlite {
+        match open_mirror(path).await {
+            Ok(mirror) => state.mirror = Some(mirror),
+            Err(e) => {
+                error!("Failed to open SQLite mirror {}: {}", path.display(), e);
+                std::process::exit(EXIT_MISCONFIGURED);
+            }
+        }
+    }
+
+    if args.once {
+        let code = match work(&args, &client, &mut state).await {
+            Ok(summary) if summary.push_failed > 0 => EXIT_INFRA_ERROR,
+            Ok(summary) if summary.failed > 0 => EXIT_BUILD_FAILED,
+            Ok(_) => 0,
+            Err(e) => {
+                error!("Error: {}", e);
+                if e.is::<Misconfigured>() {
+                    EXIT_MISCONFIGURED
+                } else {
+                    EXIT_INFRA_ERROR
+                }
+            }
+        };
+        std::process::exit(code);
+    }
+
+    let mut built = 0;
+    loop {
+        heartbeat();
+        match work(&args, &client, &mut state).await {
+            Ok(summary) => built += summary.built,
+            Err(e) => eprintln!("Error: {}", e),
+        }
+        if STOPPING.load(Ordering::SeqCst) {
+            break;
+        }
+        tokio::select! {
+            _ = beating(tokio::time::sleep(std::time::Duration::from_secs(10))) => {}
+            _ = STOP.notified() => break,
+        }
+    }
+    info!("Stopped after building {built} packages");
+
+    Ok(())
+}
+
+/// Exit early if the server rejects the token, rather than after a whole cycle of builds
+async fn check_token(client: &Client, url: &str, token: &str) {
+    let resp = client
+        .get(format!("{url}/auth_check"))
+        .header("SECRET", token)
+        .send()
+        .await;
+
+    match resp.map(|x| x.status()) {
+        Ok(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+            error!("Server {url} rejected the token, check --token / REWORKIT_SECRET_TOKEN");
+            std::process::exit(EXIT_MISCONFIGURED);
+        }
+        Ok(status) if status.is_success() => info!("Token accepted by {url}"),
+        Ok(status) => warn!("Could not verify the token, {url}/auth_check returned {status}"),
+        Err(e) => warn!("Could not verify the token: {}", e),
+    }
+}
+
+/// Milliseconds since the worker started when the work loop last showed progress
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+fn uptime() -> Duration {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed()
+}
+
+/// Record that the work loop is making progress
+fn heartbeat() {
+    HEARTBEAT.store(uptime().as_millis() as u64, Ordering::SeqCst);
+}
+
+fn since_heartbeat() -> Duration {
+    uptime().saturating_sub(Duration::from_millis(HEARTBEAT.load(Ordering::SeqCst)))
+}
+
+/// Keep the heartbeat going while waiting on `future`, only for waits that end on their
+/// own, like a build under --build-timeout-secs or a sleep
+async fn beating<F: std::future::Future>(future: F) -> F::Output {
+    tokio::pin!(future);
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
     loop {
-        if let Err(e) = work(tree_dir.clone(), &name, &client, &token, &url, &arch).await {
-            eprintln!("Error: {}", e);
+        tokio::select! {
+            output = &mut future => {
+                heartbeat();
+                return output;
+            }
+            _ = interval.tick() => heartbeat(),
+        }
+    }
+}
+
+/// Ping systemd as long as the heartbeat is younger than `timeout`, so a wedged work loop,
+/// e.g. stuck on a request that never completes, gets the worker restarted
+async fn watchdog(timeout: Duration) {
+    heartbeat();
+    let mut interval = tokio::time::interval(timeout / 2);
+    let mut stalled = false;
+    loop {
+        interval.tick().await;
+        let since = since_heartbeat();
+        if since >= timeout {
+            if !stalled {
+                error!("Work loop made no progress for {since:?}, withholding watchdog pings");
+                stalled = true;
+            }
+            continue;
+        }
+        stalled = false;
+        if let Err(e) = sd_notify::notify(&[sd_notify::NotifyState::Watchdog]) {
+            warn!("Failed to send watchdog ping: {}", e);
+        }
+    }
+}
+
+/// File in the workspace keeping what the next cycle continues from, so a restart or a
+/// --once run does not lose it
+const STATE_FILE: &str = ".reworkit-worker.json";
+
+/// The part of `WorkerState` kept in `STATE_FILE`
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct SavedState {
+    built_commit: Option<String>,
+    deferred: Vec<String>,
+    cycles_since_rebuild: u32,
+}
+
+/// The saved state, empty if there is none and the first cycle builds everything
+async fn load_state(workspace: &Path) -> SavedState {
+    let path = workspace.join(STATE_FILE);
+    let content = match tokio::fs::read(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return SavedState::default(),
+        Err(e) => {
+            warn!(
+                "Failed to read {}, building everything: {}",
+                path.display(),
+                e
+            );
+            return SavedState::default();
+        }
+    };
+
+    match serde_json::from_slice::<SavedState>(&content) {
+        Ok(saved) => {
+            if let Some(commit) = &saved.built_commit {
+                info!(
+                    "Continuing from tree commit {commit} with {} deferred packages",
+                    saved.deferred.len()
+                );
+            }
+            saved
+        }
+        Err(e) => {
+            warn!("Ignoring {}, building everything: {}", path.display(), e);
+            SavedState::default()
+        }
+    }
+}
+
+/// Write the state next cycles continue from, replacing the file at once so a crash
+/// never leaves half of it
+async fn save_state(workspace: &Path, state: &WorkerState) {
+    let saved = SavedState {
+        built_commit: state.built_commit.clone(),
+        deferred: state.deferred.clone(),
+        cycles_since_rebuild: state.cycles_since_rebuild,
+    };
+    let path = workspace.join(STATE_FILE);
+    let tmp = workspace.join(format!("{STATE_FILE}.tmp"));
+
+    let result = async {
+        tokio::fs::write(&tmp, serde_json::to_vec(&saved)?).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        anyhow::Ok(())
+    };
+    if let Err(e) = result.await {
+        warn!("Failed to save {}: {}", path.display(), e);
+    }
+}
+
+/// State carried from one cycle to the next
+#[derive(Default)]
+struct WorkerState {
+    /// Packages left over from a cycle that ran out of time
+    deferred: Vec<String>,
+    /// Log compression the server asked for
+    codec: Codec,
+    /// Local copy of the results
+    mirror: Option<SqlitePool>,
+    /// Key pushes are signed with
+    sign_key: Option<SigningKey>,
+    /// Tree commit the last finished cycle built, what the next one looks for changes since
+    built_commit: Option<String>,
+    /// Finished cycles since every package was last built, for --rebuild-every
+    cycles_since_rebuild: u32,
+}
+
+/// A configuration problem rather than a transient failure
+#[derive(Debug)]
+struct Misconfigured(String);
+
+impl Display for Misconfigured {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Misconfigured {}
+
+static CYCLE_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Set on SIGTERM or SIGINT, no new builds are started once it is
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// Wakes the wait between cycles and pushes waiting out maintenance when stopping
+static STOP: Notify = Notify::const_new();
+
+/// Let the builds in progress finish and push their logs, then exit
+async fn stop_on_signal() {
+    let signals = signal(SignalKind::terminate()).and_then(|term| {
+        let int = signal(SignalKind::interrupt())?;
+        Ok((term, int))
+    });
+    let (mut sigterm, mut sigint) = match signals {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Failed to install SIGTERM and SIGINT handlers: {}", e);
+            return;
+        }
+    };
+
+    let name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    info!("Received {name}, stopping once the builds in progress are pushed");
+    STOPPING.store(true, Ordering::SeqCst);
+    // wakes pushes waiting out maintenance
+    STOP.notify_waiters();
+    // stores a permit if the main loop is not waiting yet
+    STOP.notify_one();
+}
+
+/// Held for the duration of a cycle so two cycles never overlap
+struct CycleGuard;
+
+impl CycleGuard {
+    fn acquire() -> Option<Self> {
+        CYCLE_RUNNING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| CycleGuard)
+    }
+}
+
+impl Drop for CycleGuard {
+    fn drop(&mut self) {
+        CYCLE_RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+async fn work(args: &Args, client: &Client, state: &mut WorkerState) -> Result<CycleSummary> {
+    let Some(_guard) = CycleGuard::acquire() else {
+        info!("Previous cycle still running, skipping");
+        return Ok(CycleSummary::default());
+    };
+
+    if let Some(disable_file) = &args.disable_file {
+        if disable_file.exists() {
+            info!(
+                "Building {} is disabled by {}, skipping cycle",
+                args.arch,
+                disable_file.display()
+            );
+            return Ok(CycleSummary::default());
+        }
+    }
+
+    let tree_dir = args.workspace.join("TREE");
+    let mut previous_branch = None;
+    if args.git_ref.is_some() && args.restore_branch {
+        let branch = git(&tree_dir, &["rev-parse", "--abbrev-ref", "HEAD"]).await?;
+        // already detached, nothing to restore
+        previous_branch = Some(branch).filter(|x| x != "HEAD");
+    }
+
+    let result = run_cycle(args, client, state, tree_dir.clone()).await;
+    // a failed cycle leaves the tree detached just the same
+    if let Some(branch) = previous_branch {
+        info!("Restoring branch {branch}");
+        if let Err(e) = git(&tree_dir, &["checkout", &branch]).await {
+            if result.is_ok() {
+                return Err(e);
+            }
+            error!("Failed to restore branch {branch}: {}", e);
         }
-        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
     }
+
+    result
 }
 
-async fn work(
-    tree_dir: Arc<PathBuf>,
-    name: &str,
+/// Everything of a cycle from getting the tree on, `work` restores the branch after it
+async fn run_cycle(
+    args: &Args,
     client: &Client,
-    token: &str,
-    url: &str,
-    arch: &str,
-) -> Result<()> {
-    info!("Running git pull");
-    let git_pull = Command::new("git")
-        .arg("pull")
-        .current_dir(&*tree_dir)
-        .output()
-        .await?;
+    state: &mut WorkerState,
+    tree_dir: PathBuf,
+) -> Result<CycleSummary> {
+    let deferred = &mut state.deferred;
+    let start = Instant::now();
+
+    if let Some(git_ref) = &args.git_ref {
+        info!("Running git fetch");
+        git(&tree_dir, &["fetch", "--tags", "origin"]).await?;
+        info!("Checking out {git_ref}");
+        git(&tree_dir, &["checkout", "--detach", git_ref]).await?;
+    } else {
+        info!("Running git pull");
+        git(&tree_dir, &["pull"]).await?;
+    }
+
+    let tree_commit = git(&tree_dir, &["rev-parse", "HEAD"]).await?;
+    info!("Building tree at {tree_commit}");
+
+    let mut ignore: Vec<String> = DEFAULT_IGNORE.iter().map(|x| x.to_string()).collect();
+    ignore.extend(args.ignore.iter().cloned());
+    if let Some(path) = &args.ignore_file {
+        ignore.extend(load_ignore_file(path).await?);
+    }
 
-    ensure!(git_pull.status.success(), "Failed to run git pull");
+    let explicit = !args.packages.is_empty();
+    let pkgs = if explicit {
+        find_packages(&tree_dir, &args.packages, &ignore)?
+    } else {
+        info!("Getting packages");
+        let tree_dir = tree_dir.clone();
+        let ignore = ignore.clone();
+        spawn_blocking(move || list_packages(&tree_dir, &ignore)).await?
+    };
+
+    let rebuild = !explicit
+        && args
+            .rebuild_every
+            .is_some_and(|n| state.cycles_since_rebuild + 1 >= n);
+    if rebuild {
+        info!("Building every package, as --rebuild-every asks for");
+    }
+    let all = args.all || rebuild;
 
-    info!("Getting packages");
-    let pkgs = spawn_blocking(move || list_packages(&tree_dir)).await?;
+    // the first cycle has nothing to compare against and builds everything
+    let changed = match &state.built_commit {
+        Some(built) if !all && !explicit => {
+            match changed_packages(&tree_dir, built, &tree_commit, &ignore).await {
+                Ok(changed) => {
+                    info!("{} packages changed since {built}", changed.len());
+                    Some(changed)
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to get packages changed since {built}, building all: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let idle = changed.as_ref().is_some_and(|x| x.is_empty()) && deferred.is_empty();
+    if idle && !args.always_update_os {
+        info!("No packages changed, nothing to do");
+        state.built_commit = Some(tree_commit);
+        state.cycles_since_rebuild += 1;
+        save_state(&args.workspace, state).await;
+
+        return Ok(CycleSummary {
+            total: pkgs.len(),
+            ..Default::default()
+        });
+    }
 
     info!("Running ciel update-os");
     let ciel_update = Command::new("ciel").arg("update-os").output().await?;
-    ensure!(ciel_update.status.success(), "Failed to run ciel update-os");
-
-    for pkg in pkgs {
-        info!("Building {pkg}");
-        let ciel_build = Command::new("ciel")
-            .arg("build")
-            .arg("-i")
-            .arg(&name)
-            .arg(&pkg)
-            .output()
-            .await?;
+    if !ciel_update.status.success() {
+        ensure!(
+            args.continue_on_update_failure,
+            "Failed to run ciel update-os"
+        );
+        warn!(
+            "Failed to run ciel update-os, building against the current OS: {}",
+            String::from_utf8_lossy(&ciel_update.stderr).trim()
+        );
+    }
+
+    let instances = list_instances().await?;
+    let jobs = usize::from(args.jobs);
+    let pool: VecDeque<String> = args
+        .name
+        .iter()
+        .filter(|x| instances.contains(x))
+        .take(jobs)
+        .cloned()
+        .collect();
+    if pool.is_empty() {
+        return Err(Misconfigured(format!(
+            "None of the instances {:?} exist, available instances: {:?}",
+            args.name, instances
+        ))
+        .into());
+    }
+    if pool.len() < jobs {
+        return Err(Misconfigured(format!(
+            "--jobs {} needs as many instances, only {:?} of {:?} exist",
+            args.jobs, pool, args.name
+        ))
+        .into());
+    }
+    info!("Using instances {:?}", pool);
+
+    let overrides = match &args.instance_overrides {
+        Some(path) => load_instance_overrides(path).await?,
+        None => HashMap::new(),
+    };
+    for (pkg, instance) in &overrides {
+        if !instances.contains(instance) {
+            return Err(Misconfigured(format!(
+                "Instance {instance} for {pkg} does not exist, available instances: {instances:?}"
+            ))
+            .into());
+        }
+    }
+
+    let expected_failures = match &args.expected_failures {
+        Some(path) => load_expected_failures(path).await?,
+        None => HashSet::new(),
+    };
+
+    if args.sd_notify {
+        // no-op when not running under systemd
+        sd_notify::notify(&[sd_notify::NotifyState::Ready])?;
+    }
+
+    let mut summary = CycleSummary {
+        total: pkgs.len(),
+        ..Default::default()
+    };
+
+    // packages deferred last cycle go first, then those never built on this arch
+    let mut pkgs = pkgs;
+    let mut queue: VecDeque<String> = deferred.drain(..).filter(|x| pkgs.remove(x)).collect();
+    if let Some(changed) = &changed {
+        pkgs.retain(|x| changed.contains(x));
+    }
+    if !args.force && !all && !explicit {
+        match up_to_date_packages(client, args, &tree_dir, &tree_commit, &pkgs, &ignore).await {
+            Ok(up_to_date) => {
+                let before = pkgs.len();
+                pkgs.retain(|x| !up_to_date.contains(x));
+                info!(
+                    "Skipping {} packages already built successfully on {} and unchanged since",
+                    before - pkgs.len(),
+                    args.arch
+                );
+            }
+            Err(e) => warn!("Failed to get packages already built successfully: {}", e),
+        }
+    }
+    if args.prioritize_missing {
+        match missing_packages(client, &args.url, &args.arch, &pkgs).await {
+            Ok(missing) => {
+                info!(
+                    "{} packages have no result on {} yet",
+                    missing.len(),
+                    args.arch
+                );
+                queue.extend(missing.into_iter().filter(|x| pkgs.remove(x)));
+            }
+            Err(e) => warn!("Failed to get packages missing a result: {}", e),
+        }
+    }
+    queue.extend(pkgs);
+    let (queue, waits) = {
+        let tree_dir = tree_dir.clone();
+        let arch = args.arch.clone();
+        let queue = Vec::from(queue);
+        spawn_blocking(move || deps::order(&tree_dir, queue, &arch)).await?
+    };
+    let mut queue = VecDeque::from(queue);
+    let max_cycle_time = args.max_cycle_secs.map(Duration::from_secs);
+
+    let cycle = Cycle {
+        args,
+        client,
+        tree_dir: &tree_dir,
+        tree_commit: &tree_commit,
+        expected_failures: &expected_failures,
+        locks: pool
+            .iter()
+            .chain(overrides.values())
+            .map(|x| (x.clone(), tokio::sync::Mutex::new(())))
+            .collect(),
+        overrides: &overrides,
+        pool: Mutex::new(pool),
+        codec: Mutex::new(state.codec),
+        sign_key: state.sign_key.as_ref(),
+        mirror: state.mirror.as_ref(),
+    };
+
+    // finished this cycle, packages waiting on them can start
+    let mut done = HashSet::new();
+    let mut building = FuturesUnordered::new();
+    loop {
+        while building.len() < jobs {
+            if !queue.is_empty() && max_cycle_time.is_some_and(|max| start.elapsed() >= max) {
+                *deferred = queue.drain(..).collect();
+                warn!(
+                    "Cycle time budget exceeded, deferring {} packages to the next cycle",
+                    deferred.len()
+                );
+            }
+            if !queue.is_empty() && STOPPING.load(Ordering::SeqCst) {
+                info!(
+                    "Stopping, deferring {} packages to the next run",
+                    queue.len()
+                );
+                deferred.extend(queue.drain(..));
+            }
+            // dependencies always come first, so with nothing building the first is ready
+            let Some(idx) = queue.iter().position(|pkg| {
+                waits
+                    .get(pkg)
+                    .is_none_or(|x| x.iter().all(|dep| done.contains(dep)))
+            }) else {
+                break;
+            };
+            let pkg = queue.remove(idx).unwrap();
+            let cycle = &cycle;
+            building.push(async move { (pkg.clone(), cycle.build(pkg).await) });
+        }
+
+        let Some((pkg, result)) = building.next().await else {
+            break;
+        };
+        heartbeat();
+        let result = result?;
+        // its push was given up on the way out, build it again next run
+        if result.push_failed > 0 && STOPPING.load(Ordering::SeqCst) {
+            deferred.push(pkg.clone());
+        }
+        done.insert(pkg);
+        summary.add(result);
+    }
+    drop(building);
+    state.codec = cycle.codec.into_inner().unwrap();
+    // only the named packages were built, others may still need to be since the last cycle
+    if !explicit {
+        state.built_commit = Some(tree_commit.clone());
+        state.cycles_since_rebuild = if all {
+            0
+        } else {
+            state.cycles_since_rebuild + 1
+        };
+    }
+
+    info!(
+        "Cycle finished: {} packages, {} built, {} succeeded, {} failed, {} expected to fail, {} skipped, {} deferred, took {:?}",
+        summary.total,
+        summary.built,
+        summary.succeeded,
+        summary.failed,
+        summary.expected_failed,
+        summary.skipped,
+        deferred.len(),
+        start.elapsed()
+    );
+    save_state(&args.workspace, state).await;
+
+    Ok(summary)
+}
+
+#[derive(Default)]
+struct CycleSummary {
+    total: usize,
+    built: usize,
+    succeeded: usize,
+    failed: usize,
+    /// Failed, but listed in --expected-failures
+    expected_failed: usize,
+    /// Built but not pushed
+    skipped: usize,
+    /// Pushes that failed after all retries
+    push_failed: usize,
+}
+
+impl CycleSummary {
+    fn add(&mut self, other: CycleSummary) {
+        self.built += other.built;
+        self.succeeded += other.succeeded;
+        self.failed += other.failed;
+        self.expected_failed += other.expected_failed;
+        self.skipped += other.skipped;
+        self.push_failed += other.push_failed;
+    }
+}
+
+/// What the builds of a cycle share
+struct Cycle<'a> {
+    args: &'a Args,
+    client: &'a Client,
+    tree_dir: &'a Path,
+    tree_commit: &'a str,
+    overrides: &'a HashMap<String, String>,
+    expected_failures: &'a HashSet<String>,
+    /// Instances free to build packages without an override in, one per job
+    pool: Mutex<VecDeque<String>>,
+    /// Held while building in an instance, ciel does not build twice in one at once
+    locks: HashMap<String, tokio::sync::Mutex<()>>,
+    /// Log compression the server asked for
+    codec: Mutex<Codec>,
+    sign_key: Option<&'a SigningKey>,
+    mirror: Option<&'a SqlitePool>,
+}
+
+impl Cycle<'_> {
+    /// Build a package and push its result, returns what that adds to the summary
+    async fn build(&self, pkg: String) -> Result<CycleSummary> {
+        let args = self.args;
+        let client = self.client;
+        let mut summary = CycleSummary::default();
+
+        let pooled = !self.overrides.contains_key(&pkg);
+        let instance = match self.overrides.get(&pkg) {
+            Some(instance) => instance.clone(),
+            // at most one build per job runs, so one is always free
+            None => self.pool.lock().unwrap().pop_front().unwrap(),
+        };
+        info!("Building {pkg} in instance {instance}");
+        let build_start = Instant::now();
+        let timeout = Duration::from_secs(args.build_timeout_secs);
+        let ciel_build = {
+            let _lock = self.locks[&instance].lock().await;
+            // dropping the output future on timeout kills ciel
+            let build = build_command(args, &instance, &pkg)
+                .kill_on_drop(true)
+                .output();
+            let output = beating(tokio::time::timeout(timeout, build)).await;
+            if output.is_err() {
+                warn!("Build of {pkg} timed out after {timeout:?}, killed it");
+                stop_instance(&instance).await;
+            }
+            output
+        };
+        if pooled {
+            self.pool.lock().unwrap().push_back(instance);
+        }
+        let build_time = build_start.elapsed();
 
-        let stdout = ciel_build.stdout;
-        let stderr = ciel_build.stderr;
-        let success = ciel_build.status.success();
+        let (status, stdout, stderr) = match ciel_build {
+            Ok(output) => {
+                let output = output?;
+                (Some(output.status), output.stdout, output.stderr)
+            }
+            Err(_) => (
+                None,
+                vec![],
+                format!("Build timed out after {timeout:?} and was killed\n").into_bytes(),
+            ),
+        };
+        let success = status.is_some_and(|x| x.success());
+        // no code when ciel was killed by a signal
+        let exit_code = status.and_then(|x| x.code());
+        let expected_failure = !success && self.expected_failures.contains(&pkg);
+        let artifacts = find_artifacts(&args.workspace, &stdout);
 
         info!("is success: {}", success);
+        summary.built += 1;
+        if success {
+            summary.succeeded += 1;
+        } else if expected_failure {
+            info!("{pkg} is expected to fail on {}", args.arch);
+            summary.expected_failed += 1;
+        } else {
+            summary.failed += 1;
+        }
+
+        if let Some(path) = &args.timing_csv {
+            let status = match (success, expected_failure) {
+                (true, _) => "success",
+                (false, true) => "expected_failure",
+                (false, false) => "failed",
+            };
+            if let Err(e) = append_timing(path, &pkg, build_time, status).await {
+                warn!(
+                    "Failed to record timing of {pkg} into {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
 
+        let exit = match (exit_code, status.and_then(|x| x.signal())) {
+            (Some(code), _) => code.to_string(),
+            (None, Some(signal)) => format!("none, killed by signal {signal}"),
+            (None, None) => "none, killed on timeout".to_string(),
+        };
+        // the exit status decides, a disagreeing result line points at ciel or a wrapper
+        if status.is_some() {
+            match ciel_result(&stdout).or_else(|| ciel_result(&stderr)) {
+                Some(CielResult::Succeeded) if !success => warn!(
+                    "ciel reported {pkg} as built successfully but exited with {exit}, recording a failure"
+                ),
+                Some(CielResult::Failed(reason)) if success => warn!(
+                    "ciel reported {pkg} as failed ({reason}) but exited with 0, recording a success"
+                ),
+                _ => {}
+            }
+        }
         let mut log = vec![];
+        log.extend(format!("EXIT CODE: {exit}\n").as_bytes());
         log.extend("STDOUT:\n".as_bytes());
-        log.extend(stdout);
+        log.extend(&stdout);
         log.extend("STDERR:\n".as_bytes());
-        log.extend(stderr);
+        log.extend(&stderr);
 
-        let compress_log = match compression_log(log).await {
-            Ok(log) => log,
-            Err(e) => {
-                error!("Compress LOG got error: {}", e);
-                continue;
+        let log_bytes = log.len() as i64;
+        let unchanged = args.logs_on_change_only
+            && match last_success(client, &args.url, &pkg, &args.arch).await {
+                Ok(last) => last == Some(success),
+                Err(e) => {
+                    warn!(
+                        "Failed to get the last result of {pkg}, uploading the log: {}",
+                        e
+                    );
+                    false
+                }
+            };
+
+        let codec = *self.codec.lock().unwrap();
+        let level = args.compression_level;
+        let compressed = async {
+            let log = compression_log(log, level, codec).await?;
+            let streams = if args.split_streams {
+                Some((
+                    Bytes::from(compression_log(stdout, level, codec).await?),
+                    Bytes::from(compression_log(stderr, level, codec).await?),
+                ))
+            } else {
+                None
+            };
+            anyhow::Ok((Bytes::from(log), streams))
+        };
+        let (compress_log, streams) = if unchanged {
+            info!("Result of {pkg} unchanged, not uploading the log");
+            (None, None)
+        } else {
+            match compressed.await {
+                Ok((log, streams)) => (Some(log), streams),
+                Err(e) => {
+                    error!("Compress LOG got error: {}", e);
+                    summary.skipped += 1;
+                    return Ok(summary);
+                }
+            }
+        };
+
+        let commit_subject = if args.commit_subject {
+            match package_commit_subject(self.tree_dir, &pkg).await {
+                Ok(subject) => subject,
+                Err(e) => {
+                    warn!("Failed to get the last commit of {pkg}: {}", e);
+                    None
+                }
             }
+        } else {
+            None
         };
 
-        'a: for i in 1..=3 {
+        let report = Report {
+            pkg: pkg.clone(),
+            arch: args.arch.clone(),
+            success,
+            expected_failure,
+            tree_commit: Some(self.tree_commit.to_string()),
+            commit_subject,
+            exit_code,
+            artifacts,
+            codec,
+            log: compress_log,
+            streams,
+        };
+
+        let mut negotiated = codec;
+        let attempts = args.push_retries;
+        let mut i = 1;
+        while i <= attempts {
+            let request_id = Uuid::new_v4();
             match push_log(
                 client,
-                token,
-                arch,
-                &pkg,
-                success,
-                compress_log.clone(),
-                url,
+                &args.token,
+                &args.url,
+                &report,
+                request_id,
+                self.sign_key,
+                &mut negotiated,
             )
             .await
             {
-                Ok(_) => break 'a,
+                Ok(_) => break,
                 Err(e) => {
-                    error!("({}/3) Push LOG got error: {}", i, e);
-                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    // maintenance does not count as a failed attempt, wait for it to clear
+                    match e.downcast_ref() {
+                        Some(PushError::Maintenance(_)) if STOPPING.load(Ordering::SeqCst) => {
+                            error!(
+                                "Server is under maintenance and the worker is stopping, giving up the push of {pkg} (request id {request_id})"
+                            );
+                            summary.push_failed += 1;
+                            break;
+                        }
+                        Some(PushError::Maintenance(retry_after)) => {
+                            info!("Server is under maintenance, retrying in {:?}", retry_after);
+                            // a stop cuts the wait short, the push is then given up above
+                            tokio::select! {
+                                _ = beating(tokio::time::sleep(*retry_after)) => {}
+                                _ = STOP.notified() => {}
+                            }
+                            continue;
+                        }
+                        Some(PushError::Rejected(..)) => {
+                            error!(
+                                    "Push LOG for {pkg} rejected, not retrying (request id {request_id}): {}",
+                                    e
+                                );
+                            summary.push_failed += 1;
+                            break;
+                        }
+                        None => {}
+                    }
+
+                    error!(
+                        "({}/{attempts}) Push LOG got error (request id {request_id}): {}",
+                        i, e
+                    );
+                    if i == attempts {
+                        summary.push_failed += 1;
+                    } else {
+                        beating(tokio::time::sleep(push_backoff(i))).await;
+                    }
+                    i += 1;
                 }
             }
         }
+        // --compression overrides what the server asks for
+        if args.compression.is_none() {
+            *self.codec.lock().unwrap() = negotiated;
+        }
+
+        if let Some(mirror) = self.mirror {
+            let verified = self.sign_key.is_some();
+            if let Err(e) = mirror_result(mirror, &report, log_bytes, verified).await {
+                warn!("Failed to record {pkg} into the SQLite mirror: {}", e);
+            }
+        }
+
+        Ok(summary)
     }
+}
+
+/// Append a line to the timing CSV, writing the header first if the file is new
+async fn append_timing(path: &Path, pkg: &str, duration: Duration, status: &str) -> Result<()> {
+    let mut f = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+
+    let mut line = String::new();
+    if f.metadata().await?.len() == 0 {
+        line.push_str("package,duration_ms,status\n");
+    }
+    line.push_str(&format!("{pkg},{},{status}\n", duration.as_millis()));
+    f.write_all(line.as_bytes()).await?;
 
     Ok(())
 }
 
-async fn push_log(
+/// Open (or create) the local SQLite mirror
+///
+/// Its build_result has the server's columns but `snoozed_until`, which only `/snooze` sets.
+async fn open_mirror(path: &Path) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true);
+    let pool = SqlitePool::connect_with(options).await?;
+
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS build_result (
+name TEXT NOT NULL,
+arch TEXT NOT NULL,
+success BOOLEAN NOT NULL,
+log TEXT NOT NULL,
+finished_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+log_bytes BIGINT,
+tree_commit TEXT,
+artifacts TEXT,
+expected_failure BOOLEAN NOT NULL DEFAULT 0,
+commit_subject TEXT,
+exit_code INTEGER,
+compressed_bytes BIGINT,
+verified BOOLEAN NOT NULL DEFAULT 0,
+stdout_log TEXT,
+stderr_log TEXT,
+UNIQUE (name, arch)
+)"#,
+    )
+    .execute(&pool)
+    .await?;
+
+    // columns added after the mirror was first created, fails harmlessly if they exist
+    for column in [
+        "tree_commit TEXT",
+        "artifacts TEXT",
+        "expected_failure BOOLEAN NOT NULL DEFAULT 0",
+        "commit_subject TEXT",
+        "exit_code INTEGER",
+        "compressed_bytes BIGINT",
+        "verified BOOLEAN NOT NULL DEFAULT 0",
+        "stdout_log TEXT",
+        "stderr_log TEXT",
+    ] {
+        sqlx::query(&format!("ALTER TABLE build_result ADD COLUMN {column}"))
+            .execute(&pool)
+            .await
+            .ok();
+    }
+
+    Ok(pool)
+}
+
+/// Record a pushed result into the mirror
+///
+/// `log` is the name the server stores the log under by default, `{name}-{arch}.log`, a
+/// server with `REWORKIT_LOG_DATE_LAYOUT` or `REWORKIT_LOG_DEDUP` stores it elsewhere. Like
+/// the server, a push without a log keeps the stored sizes and streams. `verified` is
+/// whether the push was signed.
+async fn mirror_result(
+    mirror: &SqlitePool,
+    report: &Report,
+    log_bytes: i64,
+    verified: bool,
+) -> Result<()> {
+    let log_bytes = report.log.as_ref().map(|_| log_bytes);
+    let compressed_bytes = report.log.as_ref().map(|x| x.len() as i64);
+    let stream = |name| {
+        report
+            .streams
+            .as_ref()
+            .map(|_| format!("{}-{}.{name}.log", report.pkg, report.arch))
+    };
+    sqlx::query(
+        r#"INSERT INTO build_result (name, arch, success, log, finished_at, log_bytes, tree_commit, artifacts, expected_failure, commit_subject, exit_code, compressed_bytes, verified, stdout_log, stderr_log) VALUES ($1, $2, $3, $4, CURRENT_TIMESTAMP, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+ON CONFLICT (name, arch) DO UPDATE SET success=$3, log=$4, finished_at=CURRENT_TIMESTAMP, log_bytes=coalesce($5, log_bytes), tree_commit=$6, artifacts=$7, expected_failure=$8, commit_subject=$9, exit_code=$10, compressed_bytes=coalesce($11, compressed_bytes), verified=$12,
+stdout_log=CASE WHEN $5 IS NULL THEN stdout_log ELSE $13 END, stderr_log=CASE WHEN $5 IS NULL THEN stderr_log ELSE $14 END"#,
+    )
+    .bind(&report.pkg)
+    .bind(&report.arch)
+    .bind(report.success)
+    .bind(format!("{}-{}.log", report.pkg, report.arch))
+    .bind(log_bytes)
+    .bind(&report.tree_commit)
+    .bind(serde_json::to_string(&report.artifacts)?)
+    .bind(report.expected_failure)
+    .bind(&report.commit_subject)
+    .bind(report.exit_code)
+    .bind(compressed_bytes)
+    .bind(verified)
+    .bind(stream("stdout"))
+    .bind(stream("stderr"))
+    .execute(mirror)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Artifact {
+    name: String,
+    size: Option<u64>,
+}
+
+/// Packages mentioned in ciel's output, sized if the file can be found in the workspace
+fn find_artifacts(workspace: &Path, output: &[u8]) -> Vec<Artifact> {
+    let output = String::from_utf8_lossy(output);
+    let mut seen = BTreeSet::new();
+    let mut artifacts = vec![];
+
+    for word in output.split_whitespace() {
+        let word = word.trim_matches(|c: char| matches!(c, '\'' | '"' | ',' | ':' | '(' | ')'));
+        if !word.ends_with(".deb") {
+            continue;
+        }
+
+        let path = workspace.join(word);
+        let Some(name) = path.file_name().map(|x| x.to_string_lossy().to_string()) else {
+            continue;
+        };
+        if seen.insert(name.clone()) {
+            artifacts.push(Artifact {
+                name,
+                size: std::fs::metadata(&path).ok().map(|x| x.len()),
+            });
+        }
+    }
+
+    artifacts
+}
+
+/// What ciel's own result line says about a build
+#[derive(Debug, PartialEq, Eq)]
+enum CielResult {
+    Succeeded,
+    /// With the rest of the line, e.g. `status: 1`
+    Failed(String),
+}
+
+/// The last result line in ciel's output, `Build SUCCESSFUL` or `Build failed with
+/// status: <code>`, colors stripped
+fn ciel_result(output: &[u8]) -> Option<CielResult> {
+    let output = String::from_utf8_lossy(output);
+    output.lines().rev().find_map(|line| {
+        let line = strip_ansi(line);
+        let line = line.trim();
+        if line.contains("Build SUCCESSFUL") {
+            Some(CielResult::Succeeded)
+        } else {
+            let (_, reason) = line.split_once("Build failed with ")?;
+            Some(CielResult::Failed(reason.trim().to_string()))
+        }
+    })
+}
+
+/// `line` without ANSI escape sequences such as colors
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        // CSI sequences end with a letter, others are a single character
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// `ciel build`, wrapped in `ionice`/`nice` if asked to run at a lower priority
+fn build_command(args: &Args, instance: &str, pkg: &str) -> Command {
+    let mut argv = vec![];
+    if let Some(class) = args.ionice {
+        argv.extend([
+            "ionice".to_string(),
+            "-c".to_string(),
+            class.number().to_string(),
+        ]);
+    }
+    if let Some(nice) = args.nice {
+        argv.extend(["nice".to_string(), "-n".to_string(), nice.to_string()]);
+    }
+    argv.extend(["ciel", "build", "-i", instance, pkg].map(String::from));
+
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..]);
+
+    cmd
+}
+
+/// Run git in the tree, returns its trimmed stdout
+async fn git(tree_dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(tree_dir)
+        .output()
+        .await?;
+
+    ensure!(
+        output.status.success(),
+        "Failed to run git {}: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Subject of the last commit touching `pkg`'s directory, `None` if it has none
+async fn package_commit_subject(tree_dir: &Path, pkg: &str) -> Result<Option<String>> {
+    // packages live at <section>/<pkg>
+    let pathspec = format!(":(glob)*/{pkg}/**");
+    let subject = git(tree_dir, &["log", "-1", "--format=%s", "--", &pathspec]).await?;
+
+    Ok(Some(subject).filter(|x| !x.is_empty()))
+}
+
+/// Packages with files changed between two commits of the tree
+async fn changed_packages(
+    tree_dir: &Path,
+    from: &str,
+    to: &str,
+    ignore: &[String],
+) -> Result<HashSet<String>> {
+    let range = format!("{from}..{to}");
+    let paths = git(tree_dir, &["diff", "--name-only", &range]).await?;
+
+    // packages live at <section>/<pkg>, see list_packages
+    Ok(paths
+        .lines()
+        .filter_map(|x| {
+            let mut parts = x.split('/');
+            let section = parts.next()?;
+            let pkg = parts.next()?;
+            // a file right in the section is not part of a package
+            parts.next()?;
+            (!is_ignored(ignore, &Path::new(section).join(pkg))).then(|| pkg.to_string())
+        })
+        .collect())
+}
+
+/// Parse the `package = instance` lines of an overrides file, `#` starts a comment
+async fn load_instance_overrides(path: &Path) -> Result<HashMap<String, String>> {
+    let content = tokio::fs::read_to_string(path).await.map_err(|e| {
+        Misconfigured(format!(
+            "Failed to read instance overrides {}: {e}",
+            path.display()
+        ))
+    })?;
+
+    let mut overrides = HashMap::new();
+    for (no, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some((pkg, instance)) = line.split_once('=') else {
+            return Err(Misconfigured(format!(
+                "{}:{}: expected `package = instance`",
+                path.display(),
+                no + 1
+            ))
+            .into());
+        };
+        overrides.insert(pkg.trim().to_string(), instance.trim().to_string());
+    }
+
+    Ok(overrides)
+}
+
+/// Package names listed one per line, `#` starts a comment
+async fn load_expected_failures(path: &Path) -> Result<HashSet<String>> {
+    let content = tokio::fs::read_to_string(path).await.map_err(|e| {
+        Misconfigured(format!(
+            "Failed to read expected failures {}: {e}",
+            path.display()
+        ))
+    })?;
+
+    Ok(content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// Glob patterns listed one per line, `#` starts a comment
+async fn load_ignore_file(path: &Path) -> Result<Vec<String>> {
+    let content = tokio::fs::read_to_string(path).await.map_err(|e| {
+        Misconfigured(format!(
+            "Failed to read ignore patterns {}: {e}",
+            path.display()
+        ))
+    })?;
+
+    Ok(content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// Instance names from `ciel list`
+async fn list_instances() -> Result<Vec<String>> {
+    let ciel_list = Command::new("ciel").arg("list").output().await?;
+    ensure!(ciel_list.status.success(), "Failed to run ciel list");
+
+    Ok(String::from_utf8_lossy(&ciel_list.stdout)
+        .lines()
+        .filter(|line| !line.starts_with("INSTANCE"))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// How long to wait after failed push attempt `attempt`, counting from 1
+///
+/// Starts at 5s and triples each attempt up to 10 minutes, with up to 20% jitter so workers
+/// retrying after a server restart spread out.
+fn push_backoff(attempt: u32) -> Duration {
+    let base = Duration::from_secs(5)
+        .saturating_mul(3u32.saturating_pow(attempt - 1))
+        .min(Duration::from_secs(600));
+
+    base + base.mul_f64(fastrand::f64() * 0.2)
+}
+
+/// Shut an instance down, so nothing a killed build started keeps running in it
+async fn stop_instance(instance: &str) {
+    match Command::new("ciel")
+        .args(["stop", "-i", instance])
+        .output()
+        .await
+    {
+        Ok(x) if x.status.success() => {}
+        Ok(x) => warn!(
+            "Failed to stop instance {instance}: {}",
+            String::from_utf8_lossy(&x.stderr).trim()
+        ),
+        Err(e) => warn!("Failed to stop instance {instance}: {}", e),
+    }
+}
+
+/// A build result to push to the server
+struct Report {
+    pkg: String,
+    arch: String,
+    success: bool,
+    /// Failed, but the package is expected to fail on this arch
+    expected_failure: bool,
+    /// Commit of the tree the package was built from
+    tree_commit: Option<String>,
+    /// Subject of the last commit touching the package's directory
+    commit_subject: Option<String>,
+    /// Exit code of `ciel build`, `None` if it was killed
+    exit_code: Option<i32>,
+    artifacts: Vec<Artifact>,
+    codec: Codec,
+    /// Compressed build log, `None` to keep the one the server has
+    ///
+    /// Shared rather than copied into every push attempt.
+    log: Option<Bytes>,
+    /// Compressed stdout and stderr of the build, sent along with the log
+    streams: Option<(Bytes, Bytes)>,
+}
+
+/// Packages of `pkgs` the server has no result for on `arch`
+async fn missing_packages(
     client: &Client,
-    token: &str,
+    url: &str,
     arch: &str,
-    pkg: &str,
+    pkgs: &BTreeSet<String>,
+) -> Result<Vec<String>> {
+    let body = client
+        .post(format!("{url}/missing"))
+        .query(&[("arch", arch)])
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(pkgs)?)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[derive(Deserialize)]
+struct ServerResult {
+    name: String,
     success: bool,
-    compress_log: Vec<u8>,
+    tree_commit: Option<String>,
+}
+
+/// Packages of `pkgs` the server has a successful result for on this arch, built from a
+/// tree commit they have not changed since
+async fn up_to_date_packages(
+    client: &Client,
+    args: &Args,
+    tree_dir: &Path,
+    tree_commit: &str,
+    pkgs: &BTreeSet<String>,
+    ignore: &[String],
+) -> Result<HashSet<String>> {
+    let body = client
+        .post(format!("{}/get_many", args.url))
+        .query(&[("arch", &args.arch)])
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(pkgs)?)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let results: Vec<ServerResult> = serde_json::from_slice(&body)?;
+
+    // results without a tree commit could be from any commit and are rebuilt
+    let mut by_commit: HashMap<&str, Vec<&str>> = HashMap::new();
+    for result in results.iter().filter(|x| x.success) {
+        if let Some(commit) = &result.tree_commit {
+            by_commit.entry(commit).or_default().push(&result.name);
+        }
+    }
+
+    let mut up_to_date = HashSet::new();
+    for (commit, names) in by_commit {
+        let changed = if commit == tree_commit {
+            HashSet::new()
+        } else {
+            match changed_packages(tree_dir, commit, tree_commit, ignore).await {
+                Ok(changed) => changed,
+                // such as a commit this tree does not have
+                Err(e) => {
+                    warn!(
+                        "Rebuilding {} packages last built from {commit}, failed to get what changed since: {}",
+                        names.len(),
+                        e
+                    );
+                    continue;
+                }
+            }
+        };
+        up_to_date.extend(
+            names
+                .into_iter()
+                .filter(|x| !changed.contains(*x))
+                .map(String::from),
+        );
+    }
+
+    Ok(up_to_date)
+}
+
+#[derive(Deserialize)]
+struct LastResult {
+    arch: String,
+    success: bool,
+}
+
+/// Whether the server's last build of `pkg` on `arch` succeeded, `None` if it has none
+async fn last_success(client: &Client, url: &str, pkg: &str, arch: &str) -> Result<Option<bool>> {
+    let resp = client
+        .get(format!("{url}/get"))
+        .query(&[("name", pkg), ("arch", arch)])
+        .send()
+        .await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let body = resp.error_for_status()?.bytes().await?;
+    let results: Vec<LastResult> = serde_json::from_slice(&body)?;
+
+    // servers predating the arch filter return every arch
+    Ok(results
+        .into_iter()
+        .find(|x| x.arch == arch)
+        .map(|x| x.success))
+}
+
+/// Compress and push a log from disk, for backfilling or resubmitting a result
+async fn push_file(client: &Client, push: &PushArgs) -> Result<()> {
+    let log = tokio::fs::read(&push.log_file).await?;
+    let codec = push.compression;
+    let report = Report {
+        pkg: push.package.clone(),
+        arch: push.arch.clone(),
+        success: push.success,
+        expected_failure: false,
+        tree_commit: push.tree_commit.clone(),
+        commit_subject: None,
+        exit_code: None,
+        artifacts: vec![],
+        codec,
+        log: Some(compression_log(log, None, codec).await?.into()),
+        streams: None,
+    };
+
+    let sign_key = match &push.sign_key {
+        Some(path) => Some(load_sign_key(path).await?),
+        None => None,
+    };
+
+    let request_id = Uuid::new_v4();
+    let mut negotiated = codec;
+    push_log(
+        client,
+        &push.token,
+        &push.url,
+        &report,
+        request_id,
+        sign_key.as_ref(),
+        &mut negotiated,
+    )
+    .await
+    .with_context(|| format!("Failed to push {} (request id {request_id})", push.package))?;
+    info!("Pushed {} for {}", push.package, push.arch);
+
+    Ok(())
+}
+
+/// Read a hex encoded ed25519 secret key
+async fn load_sign_key(path: &Path) -> Result<SigningKey> {
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| Misconfigured(format!("Failed to read sign key {}: {e}", path.display())))?;
+    let key = hex::decode(content.trim())
+        .ok()
+        .and_then(|x| <[u8; 32]>::try_from(x).ok())
+        .map(|x| SigningKey::from_bytes(&x))
+        .ok_or_else(|| {
+            Misconfigured(format!(
+                "{} is not a hex encoded ed25519 secret key",
+                path.display()
+            ))
+        })?;
+    info!(
+        "Signing pushes, public key {}",
+        hex::encode(key.verifying_key().as_bytes())
+    );
+
+    Ok(key)
+}
+
+/// The bytes that are signed, must stay in sync with `Signed::payload` in the server
+fn signed_payload(report: &Report, artifacts: &str) -> Vec<u8> {
+    let hash = |x: &Bytes| format!("{:x}", Sha256::digest(x));
+    let log_hash = report.log.as_ref().map(hash).unwrap_or_default();
+    let (stdout_hash, stderr_hash) = report
+        .streams
+        .as_ref()
+        .map(|(stdout, stderr)| (hash(stdout), hash(stderr)))
+        .unwrap_or_default();
+    let exit_code = report.exit_code.map(|x| x.to_string()).unwrap_or_default();
+
+    [
+        "reworkit-v2",
+        &report.pkg,
+        &report.arch,
+        &report.success.to_string(),
+        &report.expected_failure.to_string(),
+        report.tree_commit.as_deref().unwrap_or_default(),
+        artifacts,
+        &log_hash,
+        &stdout_hash,
+        &stderr_hash,
+        &exit_code,
+        // last, so a newline in it cannot be taken for the end of another field
+        report.commit_subject.as_deref().unwrap_or_default(),
+    ]
+    .join("\n")
+    .into_bytes()
+}
+
+/// Push a report, `negotiated` is updated to the codec the server asks for next time
+async fn push_log(
+    client: &Client,
+    token: &str,
     url: &str,
+    report: &Report,
+    request_id: Uuid,
+    sign_key: Option<&SigningKey>,
+    negotiated: &mut Codec,
 ) -> Result<()> {
-    let form = multipart::Form::new()
+    let pkg = &report.pkg;
+    let artifacts = serde_json::to_string(&report.artifacts)?;
+    let mut form = multipart::Form::new()
         .text("package", pkg.to_string())
-        .text("arch", arch.to_string())
-        .text("success", success.to_string())
-        .part(
+        .text("arch", report.arch.clone())
+        .text("success", report.success.to_string())
+        .text("expected_failure", report.expected_failure.to_string())
+        .text("artifacts", artifacts.clone())
+        .text("compression", report.codec.name());
+    if let Some(tree_commit) = &report.tree_commit {
+        form = form.text("tree_commit", tree_commit.clone());
+    }
+    if let Some(subject) = &report.commit_subject {
+        form = form.text("commit_subject", subject.clone());
+    }
+    if let Some(code) = report.exit_code {
+        form = form.text("exit_code", code.to_string());
+    }
+    if let Some(log) = &report.log {
+        form = form.part(
             "log",
-            Part::bytes(compress_log).file_name(format!("{pkg}.log")),
+            Part::stream_with_length(log.clone(), log.len() as u64).file_name(format!("{pkg}.log")),
         );
+    } else {
+        // --logs-on-change-only and the status did not change
+        form = form.text("keep_log", "true");
+    }
+    if let Some((stdout, stderr)) = &report.streams {
+        for (name, stream) in [("stdout", stdout), ("stderr", stderr)] {
+            form = form.part(
+                name,
+                Part::stream_with_length(stream.clone(), stream.len() as u64)
+                    .file_name(format!("{pkg}.{name}.log")),
+            );
+        }
+    }
 
-    client
+    let mut req = client
         .post(format!("{url}/push_log"))
         .header("SECRET", token)
-        .multipart(form)
-        .send()
-        .await?;
+        .header("X-Request-Id", request_id.to_string())
+        .header(OFFERED_CODECS, "zstd, gzip");
+    if let Some(key) = sign_key {
+        let signature = key.sign(&signed_payload(report, &artifacts));
+        req = req.header(SIGNATURE, hex::encode(signature.to_bytes()));
+    }
+    let resp = req.multipart(form).send().await?;
+
+    // servers predating codec negotiation only understand gzip
+    *negotiated = resp
+        .headers()
+        .get(CHOSEN_CODEC)
+        .and_then(|x| x.to_str().ok())
+        .and_then(Codec::parse)
+        .unwrap_or_default();
+
+    if resp.status() == StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(60);
+
+        return Err(PushError::Maintenance(Duration::from_secs(retry_after)).into());
+    }
+
+    let status = resp.status();
+    // 422 means the log arrived corrupted, which another upload may not
+    if status.is_client_error()
+        && status != StatusCode::TOO_MANY_REQUESTS
+        && status != StatusCode::UNPROCESSABLE_ENTITY
+    {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(PushError::Rejected(status, body).into());
+    }
+
+    ensure!(
+        status.is_success(),
+        "Server returned {}: {}",
+        status,
+        resp.text().await.unwrap_or_default()
+    );
 
     Ok(())
 }
 
-async fn compression_log(log: Vec<u8>) -> Result<Vec<u8>> {
+#[derive(Debug)]
+enum PushError {
+    /// The server is under maintenance, retry after the given duration
+    Maintenance(Duration),
+    /// The server refused the push, retrying will not help
+    Rejected(StatusCode, String),
+}
+
+impl Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::Maintenance(retry_after) => {
+                write!(
+                    f,
+                    "Server is under maintenance, retry after {:?}",
+                    retry_after
+                )
+            }
+            PushError::Rejected(status, body) => {
+                write!(f, "Server rejected the push with {status}: {body}")?;
+                match *status {
+                    StatusCode::UNAUTHORIZED if body.contains("signature") => {
+                        write!(f, " (check --sign-key / REWORKIT_SIGN_KEY)")
+                    }
+                    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                        write!(f, " (check --token / REWORKIT_SECRET_TOKEN)")
+                    }
+                    StatusCode::PAYLOAD_TOO_LARGE => {
+                        write!(f, " (log exceeds the server upload limit)")
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for PushError {}
+
+/// Header listing the codecs this worker can compress logs with
+const OFFERED_CODECS: &str = "X-Reworkit-Log-Codecs";
+/// Header the server answers with, naming the codec to use
+const CHOSEN_CODEC: &str = "X-Reworkit-Log-Codec";
+/// Header carrying the hex encoded ed25519 signature of a push
+const SIGNATURE: &str = "X-Reworkit-Signature";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Codec {
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+async fn compression_log(log: Vec<u8>, level: Option<i32>, codec: Codec) -> Result<Vec<u8>> {
+    // maximum compression is cheap for small logs but takes seconds on huge ones
+    const MB: usize = 1024 * 1024;
+    let level = level.unwrap_or(match log.len() {
+        n if n < MB => 9,
+        n if n < 50 * MB => 6,
+        _ => 3,
+    });
+
     let mut compress_log = vec![];
-    let mut encoder = GzipEncoder::new(&mut compress_log);
-    encoder.write_all(&log).await?;
-    encoder.shutdown().await?;
+    match codec {
+        Codec::Gzip => {
+            let mut encoder = GzipEncoder::with_quality(&mut compress_log, Level::Precise(level));
+            encoder.write_all(&log).await?;
+            encoder.shutdown().await?;
+        }
+        Codec::Zstd => {
+            let mut encoder = ZstdEncoder::with_quality(&mut compress_log, Level::Precise(level));
+            encoder.write_all(&log).await?;
+            encoder.shutdown().await?;
+        }
+    }
 
     Ok(compress_log)
 }
 
-fn list_packages(tree_dir: &Path) -> Vec<String> {
-    let mut pkgs = vec![];
-    for entry in WalkDir::new(tree_dir).min_depth(2).max_depth(2) {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-            if path.to_string_lossy().contains("/.git")
-                || path.starts_with(tree_dir.join("groups"))
-                || path.starts_with(tree_dir.join("assets"))
-            {
-                continue;
-            }
+/// The packages named on the command line, failing if the tree has no directory for one
+fn find_packages(tree_dir: &Path, names: &[String], ignore: &[String]) -> Result<BTreeSet<String>> {
+    let sections: Vec<PathBuf> = std::fs::read_dir(tree_dir)?
+        .flatten()
+        .map(|x| PathBuf::from(x.file_name()))
+        .filter(|x| tree_dir.join(x).is_dir())
+        .collect();
 
-            if entry.file_type().is_dir() {
-                let package_name = entry.file_name().to_string_lossy().to_string();
-                pkgs.push(package_name);
+    let missing: Vec<_> = names
+        .iter()
+        .filter(|name| {
+            name.contains('/')
+                || !sections.iter().any(|section| {
+                    let path = section.join(name);
+                    tree_dir.join(&path).is_dir() && !is_ignored(ignore, &path)
+                })
+        })
+        .collect();
+    if !missing.is_empty() {
+        return Err(Misconfigured(format!(
+            "Packages {missing:?} not found in {}",
+            tree_dir.display()
+        ))
+        .into());
+    }
+
+    Ok(names.iter().cloned().collect())
+}
+
+fn list_packages(tree_dir: &Path, ignore: &[String]) -> BTreeSet<String> {
+    let mut pkgs = BTreeSet::new();
+    for entry in WalkDir::new(tree_dir)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .flatten()
+    {
+        let path = entry.path();
+        let relative = path.strip_prefix(tree_dir).unwrap_or(path);
+        if is_ignored(ignore, relative) {
+            continue;
+        }
+
+        if entry.file_type().is_dir() {
+            let package_name = entry.file_name().to_string_lossy().to_string();
+            if !pkgs.insert(package_name) {
+                warn!(
+                    "Duplicate package {} found at {}, please check the tree layout",
+                    entry.file_name().to_string_lossy(),
+                    path.display()
+                );
             }
         }
     }
 
     pkgs
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::Arc,
+    };
+
+    /// Stands in for the server, answering every request with `body` as JSON, returns its
+    /// url and the requests it got
+    fn fake_server(body: String) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(x) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = x.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut content = vec![0; length];
+                reader.read_exact(&mut content).unwrap();
+                request.push_str(&String::from_utf8_lossy(&content));
+                seen.lock().unwrap().push(request);
+
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn run_git(tree: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@localhost"])
+            .args(args)
+            .current_dir(tree)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?} failed");
+
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn write_file(tree: &Path, path: &str, content: &str) {
+        let path = tree.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn commit(tree: &Path) -> String {
+        run_git(tree, &["add", "-A"]);
+        run_git(tree, &["commit", "-qm", "update"]);
+        run_git(tree, &["rev-parse", "HEAD"])
+    }
+
+    fn test_args(workspace: &Path, url: &str) -> Args {
+        Args::parse_from([
+            "worker",
+            "-d",
+            &workspace.to_string_lossy(),
+            "-a",
+            "amd64",
+            "-u",
+            url,
+            "-t",
+            "s3cret",
+        ])
+    }
+
+    #[tokio::test]
+    async fn branch_is_restored_after_a_failed_cycle() {
+        let workspace = tempfile::tempdir().unwrap();
+        let tree = workspace.path().join("TREE");
+        std::fs::create_dir(&tree).unwrap();
+        run_git(&tree, &["init", "-q", "-b", "stable"]);
+        write_file(&tree, "app-misc/foo/spec", "VER=1");
+        commit(&tree);
+        run_git(&tree, &["tag", "v1"]);
+        run_git(&tree, &["remote", "add", "origin", "."]);
+
+        let mut args = test_args(workspace.path(), "http://127.0.0.1:1");
+        args.git_ref = Some("v1".to_string());
+        args.restore_branch = true;
+        // fails once v1 is checked out
+        args.ignore_file = Some(workspace.path().join("missing"));
+        let result = work(&args, &Client::new(), &mut WorkerState::default()).await;
+        assert!(result.is_err());
+        assert_eq!(
+            run_git(&tree, &["rev-parse", "--abbrev-ref", "HEAD"]),
+            "stable"
+        );
+    }
+
+    #[tokio::test]
+    async fn successes_unchanged_since_are_up_to_date() {
+        let workspace = tempfile::tempdir().unwrap();
+        let tree = workspace.path().join("TREE");
+        std::fs::create_dir(&tree).unwrap();
+        run_git(&tree, &["init", "-q"]);
+        for pkg in ["foo", "bar", "baz", "qux", "quux"] {
+            write_file(&tree, &format!("app-misc/{pkg}/spec"), "VER=1");
+        }
+        let old = commit(&tree);
+        write_file(&tree, "app-misc/bar/spec", "VER=2");
+        write_file(&tree, "app-misc/quux/spec", "VER=2");
+        let head = commit(&tree);
+
+        let results = serde_json::json!([
+            {"name": "foo", "arch": "amd64", "success": true, "tree_commit": old},
+            {"name": "bar", "arch": "amd64", "success": true, "tree_commit": old},
+            {"name": "baz", "arch": "amd64", "success": false, "tree_commit": head},
+            {"name": "qux", "arch": "amd64", "success": true, "tree_commit": null},
+            {"name": "quux", "arch": "amd64", "success": true, "tree_commit": head},
+            {"name": "corge", "arch": "amd64", "success": true, "tree_commit": "0".repeat(40)},
+        ]);
+        let (url, requests) = fake_server(results.to_string());
+        let args = test_args(workspace.path(), &url);
+        let pkgs = ["foo", "bar", "baz", "qux", "quux", "corge"]
+            .map(String::from)
+            .into();
+        let ignore = DEFAULT_IGNORE.map(String::from);
+
+        let up_to_date = up_to_date_packages(&Client::new(), &args, &tree, &head, &pkgs, &ignore)
+            .await
+            .unwrap();
+        // bar changed since, baz failed, and qux and corge are from unknown commits
+        assert_eq!(up_to_date, ["foo", "quux"].map(String::from).into());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("POST /get_many?arch=amd64 "));
+        assert!(requests[0].ends_with(r#"["bar","baz","corge","foo","quux","qux"]"#));
+    }
+
+    #[test]
+    fn list_packages_skips_ignored_directories() {
+        let tree = tempfile::tempdir().unwrap();
+        for dir in [
+            "app-web/foo",
+            "app-web/bar",
+            "core-base/baz",
+            "extra-scripts/tool",
+            "app-web/.gitkeep",
+            "groups/base",
+            "assets/logo",
+            ".github/workflows",
+        ] {
+            std::fs::create_dir_all(tree.path().join(dir)).unwrap();
+        }
+
+        let mut ignore = DEFAULT_IGNORE.map(String::from).to_vec();
+        let all: Vec<_> = list_packages(tree.path(), &ignore).into_iter().collect();
+        assert_eq!(all, ["bar", "baz", "foo", "tool"]);
+
+        ignore.extend(["extra-*", "app-web/f*"].map(String::from));
+        let pkgs: Vec<_> = list_packages(tree.path(), &ignore).into_iter().collect();
+        assert_eq!(pkgs, ["bar", "baz"]);
+
+        let err = find_packages(tree.path(), &["foo".to_string()], &ignore).unwrap_err();
+        assert!(err.is::<Misconfigured>());
+    }
+
+    #[tokio::test]
+    async fn compressed_logs_round_trip() {
+        use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+        use tokio::io::AsyncReadExt;
+
+        let log = b"+ make -j8\nbuild finished\n".repeat(100);
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            let compressed = compression_log(log.clone(), None, codec).await.unwrap();
+            let mut decoded = vec![];
+            match codec {
+                Codec::Gzip => GzipDecoder::new(&compressed[..])
+                    .read_to_end(&mut decoded)
+                    .await
+                    .unwrap(),
+                Codec::Zstd => ZstdDecoder::new(&compressed[..])
+                    .read_to_end(&mut decoded)
+                    .await
+                    .unwrap(),
+            };
+            assert!(decoded == log, "{codec:?} round trip differs");
+            assert_eq!(Codec::parse(codec.name()), Some(codec));
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_goes_on_only_while_beating() {
+        heartbeat();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(since_heartbeat() >= Duration::from_millis(100));
+
+        let waiting = tokio::spawn(beating(tokio::time::sleep(Duration::from_millis(2500))));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(since_heartbeat() < Duration::from_millis(1000));
+        waiting.await.unwrap();
+        assert!(since_heartbeat() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn ciel_result_lines() {
+        assert_eq!(
+            ciel_result(b"building\n\x1b[1;32m[INFO]:\x1b[0m Build SUCCESSFUL\n"),
+            Some(CielResult::Succeeded)
+        );
+        assert_eq!(
+            ciel_result(b"\x1b[31m[ERROR]:\x1b[0m Build failed with status: 1\n"),
+            Some(CielResult::Failed("status: 1".to_string()))
+        );
+        // an earlier successful stage does not count once the build failed
+        assert_eq!(
+            ciel_result(b"Build SUCCESSFUL\nBuild failed with status: 2\ntrailing output\n"),
+            Some(CielResult::Failed("status: 2".to_string()))
+        );
+        assert_eq!(ciel_result(b"building bar\nexit 1\n"), None);
+        assert_eq!(strip_ansi("\x1b[1;32mok\x1b[0m done"), "ok done");
+    }
+
+    #[tokio::test]
+    async fn mirror_keeps_the_size_of_a_kept_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirror = open_mirror(&dir.path().join("mirror.db")).await.unwrap();
+        let mut report = Report {
+            pkg: "foo".to_string(),
+            arch: "amd64".to_string(),
+            success: false,
+            expected_failure: false,
+            tree_commit: Some("0123456789abcdef".to_string()),
+            commit_subject: None,
+            exit_code: Some(1),
+            artifacts: vec![],
+            codec: Codec::Gzip,
+            log: Some(Bytes::from_static(b"compressed")),
+            streams: Some((Bytes::from_static(b"out"), Bytes::from_static(b"err"))),
+        };
+        mirror_result(&mirror, &report, 100, false).await.unwrap();
+        report.success = true;
+        report.exit_code = Some(0);
+        report.log = None;
+        report.streams = None;
+        mirror_result(&mirror, &report, 200, true).await.unwrap();
+
+        let (success, log, log_bytes, exit_code): (bool, String, i64, i32) = sqlx::query_as(
+            "SELECT success, log, log_bytes, exit_code FROM build_result WHERE name = 'foo'",
+        )
+        .fetch_one(&mirror)
+        .await
+        .unwrap();
+        assert!(success);
+        assert_eq!(log, "foo-amd64.log");
+        assert_eq!(log_bytes, 100);
+        assert_eq!(exit_code, 0);
+
+        let (compressed_bytes, verified, stdout_log, stderr_log): (i64, bool, String, String) =
+            sqlx::query_as(
+                "SELECT compressed_bytes, verified, stdout_log, stderr_log FROM build_result WHERE name = 'foo'",
+            )
+            .fetch_one(&mirror)
+            .await
+            .unwrap();
+        assert_eq!(compressed_bytes, b"compressed".len() as i64);
+        assert!(verified);
+        assert_eq!(stdout_log, "foo-amd64.stdout.log");
+        assert_eq!(stderr_log, "foo-amd64.stderr.log");
+    }
+
+    #[test]
+    fn signed_payload_covers_every_field() {
+        let mut report = Report {
+            pkg: "foo".to_string(),
+            arch: "amd64".to_string(),
+            success: true,
+            expected_failure: false,
+            tree_commit: Some("0123456789abcdef".to_string()),
+            commit_subject: Some("foo: update to 1.0".to_string()),
+            exit_code: Some(0),
+            artifacts: vec![],
+            codec: Codec::Gzip,
+            log: Some(Bytes::from_static(b"log")),
+            streams: Some((Bytes::from_static(b"out"), Bytes::from_static(b"err"))),
+        };
+        // must match `Signed::payload` in the server
+        assert_eq!(
+            String::from_utf8(signed_payload(&report, "[]")).unwrap(),
+            "reworkit-v2
+foo
+amd64
+true
+false
+0123456789abcdef
+[]
+836ff184e7b41b1e13cb5fd89fa1de98dbbab99e9d2918913ff43b86a5c7c213
+762069bc07a6e1b5df123a5ae7bd91c10daa04694fbaa17fba0cd6a8dcce8f22
+d9eb253e06987fa74a5d3189f73d9f7a8104cca786fafbb52bc9555972f5477f
+0
+foo: update to 1.0"
+        );
+
+        let signed = signed_payload(&report, "[]");
+        report.streams = Some((Bytes::from_static(b"err"), Bytes::from_static(b"out")));
+        assert_ne!(signed_payload(&report, "[]"), signed);
+        report.streams = Some((Bytes::from_static(b"out"), Bytes::from_static(b"err")));
+        report.exit_code = Some(1);
+        assert_ne!(signed_payload(&report, "[]"), signed);
+        report.exit_code = Some(0);
+        report.commit_subject = Some("foo: update to 2.0".to_string());
+        assert_ne!(signed_payload(&report, "[]"), signed);
+    }
+}